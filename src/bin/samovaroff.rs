@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+use samovaroff_broker::protocol::{Command, FetchOpts, RecordLine, Response};
+
+// сколько строк уходит в одном MPUB
+const PUB_BATCH: usize = 1000;
+// размер страницы FETCH при tail
+const TAIL_PAGE: usize = 1000;
+
+#[derive(Parser)]
+#[command(version, about = "samovaroff broker client")]
+struct Cli {
+    #[arg(long, short, env = "SAMOVAROFF_ADDR", default_value = "127.0.0.1:7001")]
+    addr: String,
+    #[arg(long, short, value_enum, default_value_t = Format::Raw)]
+    format: Format,
+    #[command(subcommand)]
+    command: ClientCommand,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    Raw,
+    Json,
+    Table,
+}
+
+#[derive(Subcommand)]
+enum ClientCommand {
+    #[command(about = "Check that the broker answers")]
+    Ping,
+    #[command(about = "Publish one message per input line from files or stdin")]
+    Pub {
+        topic: String,
+        #[arg(help = "Input files, stdin if none or '-'")]
+        files: Vec<PathBuf>,
+        #[arg(long, default_value_t = 0)]
+        prio: u8,
+    },
+    #[command(about = "Read one page of records")]
+    Fetch {
+        topic: String,
+        #[arg(long, help = "Start offset, group offset or 0 if not set")]
+        offset: Option<u64>,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+        #[arg(long)]
+        group: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        prio: u8,
+        #[arg(long)]
+        headers: bool,
+    },
+    #[command(about = "Print the last records of a topic, -f to keep following")]
+    Tail {
+        topic: String,
+        #[arg(short, long)]
+        follow: bool,
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: u64,
+        #[arg(long, default_value_t = 500)]
+        interval_ms: u64,
+        #[arg(long, default_value_t = 0)]
+        prio: u8,
+        #[arg(long)]
+        headers: bool,
+    },
+    #[command(about = "List topics")]
+    Topics,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Соединение с брокером: команда строкой, ответ - строки данных и итоговая строка `Response`.
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    fn connect(addr: &str) -> Result<Self> {
+        let writer = TcpStream::connect(addr).map_err(|e| format!("connect {}: {}", addr, e))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Conn { reader, writer })
+    }
+
+    fn send(&mut self, cmd: &Command) -> Result<()> {
+        writeln!(self.writer, "{}", cmd)?;
+        Ok(())
+    }
+
+    fn read_reply(&mut self) -> Result<(Vec<String>, Response)> {
+        let mut data = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err("connection closed by broker".into());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            match Response::parse(line) {
+                Some(r) if r.is_err() => return Err(format!("broker: {}", r).into()),
+                Some(r) => return Ok((data, r)),
+                None => data.push(line.to_string()),
+            }
+        }
+    }
+
+    fn call(&mut self, cmd: &Command) -> Result<(Vec<String>, Response)> {
+        self.send(cmd)?;
+        self.read_reply()
+    }
+}
+
+#[derive(Serialize)]
+struct RecordOut<'a> {
+    topic: &'a str,
+    offset: u64,
+    id: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<&'a str, &'a str>,
+    payload: &'a str,
+}
+
+/// Печать записей в выбранном формате; шапка таблицы - один раз.
+struct Printer {
+    format: Format,
+    header_done: bool,
+}
+
+impl Printer {
+    fn record(&mut self, topic: &str, r: &RecordLine) -> Result<()> {
+        let headers: BTreeMap<&str, &str> = r
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        match self.format {
+            Format::Raw => println!("{}", r.payload),
+            Format::Json => {
+                let out = RecordOut {
+                    topic,
+                    offset: r.offset,
+                    id: r.id,
+                    headers,
+                    payload: &r.payload,
+                };
+                println!("{}", serde_json::to_string(&out)?);
+            }
+            Format::Table => {
+                if !self.header_done {
+                    println!("{:>10}  {:>10}  {:<24}  PAYLOAD", "OFFSET", "ID", "HEADERS");
+                    self.header_done = true;
+                }
+                let headers = headers
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let headers = if headers.is_empty() { "-" } else { &headers };
+                println!(
+                    "{:>10}  {:>10}  {:<24}  {}",
+                    r.offset, r.id, headers, r.payload
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let mut conn = Conn::connect(&cli.addr)?;
+    let mut printer = Printer {
+        format: cli.format,
+        header_done: false,
+    };
+
+    match cli.command {
+        ClientCommand::Ping => ping(&mut conn, cli.format),
+        ClientCommand::Pub { topic, files, prio } => {
+            publish(&mut conn, cli.format, topic, files, prio)
+        }
+        ClientCommand::Fetch {
+            topic,
+            offset,
+            limit,
+            group,
+            prio,
+            headers,
+        } => {
+            // без offset-а и группы читаем с начала
+            let offset = offset.or(group.is_none().then_some(0));
+            let cmd = Command::Fetch {
+                topic: topic.clone(),
+                offset,
+                limit,
+                opts: fetch_opts(prio, headers, group),
+            };
+            let (lines, _) = conn.call(&cmd)?;
+            print_records(&mut printer, &topic, &lines, headers)
+        }
+        ClientCommand::Tail {
+            topic,
+            follow,
+            lines,
+            interval_ms,
+            prio,
+            headers,
+        } => {
+            let opts = fetch_opts(prio, headers, None);
+            let interval = follow.then(|| Duration::from_millis(interval_ms));
+            tail(&mut conn, &mut printer, &topic, opts, lines, interval)
+        }
+        ClientCommand::Topics => topics(&mut conn, cli.format),
+    }
+}
+
+fn fetch_opts(prio: u8, headers: bool, group: Option<String>) -> FetchOpts {
+    FetchOpts {
+        headers,
+        prio,
+        max_bytes: None,
+        group,
+        reset: None,
+    }
+}
+
+fn print_records(
+    printer: &mut Printer,
+    topic: &str,
+    lines: &[String],
+    headers: bool,
+) -> Result<()> {
+    for line in lines {
+        let r = RecordLine::parse(line, headers)
+            .ok_or_else(|| format!("unexpected line from broker: {}", line))?;
+        printer.record(topic, &r)?;
+    }
+    Ok(())
+}
+
+fn ping(conn: &mut Conn, format: Format) -> Result<()> {
+    let started = Instant::now();
+    conn.call(&Command::Ping)?;
+    let rtt_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match format {
+        Format::Json => println!("{}", serde_json::json!({ "ok": true, "rtt_ms": rtt_ms })),
+        _ => println!("OK {:.2}ms", rtt_ms),
+    }
+    Ok(())
+}
+
+fn publish(
+    conn: &mut Conn,
+    format: Format,
+    topic: String,
+    files: Vec<PathBuf>,
+    prio: u8,
+) -> Result<()> {
+    let mut inputs: Vec<Box<dyn BufRead>> = Vec::new();
+    if files.is_empty() {
+        inputs.push(Box::new(std::io::stdin().lock()));
+    }
+    for path in files {
+        if path.as_os_str() == "-" {
+            inputs.push(Box::new(std::io::stdin().lock()));
+        } else {
+            let f = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            inputs.push(Box::new(BufReader::new(f)));
+        }
+    }
+
+    let mut batch: Vec<String> = Vec::with_capacity(PUB_BATCH);
+    for input in inputs {
+        for line in input.lines() {
+            batch.push(line?);
+            if batch.len() == PUB_BATCH {
+                publish_batch(conn, format, &topic, prio, &mut batch)?;
+            }
+        }
+    }
+    publish_batch(conn, format, &topic, prio, &mut batch)
+}
+
+fn publish_batch(
+    conn: &mut Conn,
+    format: Format,
+    topic: &str,
+    prio: u8,
+    batch: &mut Vec<String>,
+) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    // MPUB <topic> <count>, за ним <count> строк
+    conn.send(&Command::MPub {
+        topic: topic.to_string(),
+        count: batch.len(),
+        prio,
+    })?;
+    for msg in batch.iter() {
+        writeln!(conn.writer, "{}", msg)?;
+    }
+    let (_, r) = conn.read_reply()?;
+
+    let count = batch.len();
+    batch.clear();
+    match (format, r) {
+        (Format::Json, Response::AckRange(first, last)) => println!(
+            "{}",
+            serde_json::json!({ "topic": topic, "first": first, "last": last })
+        ),
+        (Format::Json, _) => println!("{}", serde_json::json!({ "topic": topic, "count": count })),
+        (_, r) => println!("{}", r),
+    }
+    Ok(())
+}
+
+/// `tail`: последние `n` записей; с `follow` (интервал опроса) дальше ждем новые.
+fn tail(
+    conn: &mut Conn,
+    printer: &mut Printer,
+    topic: &str,
+    opts: FetchOpts,
+    n: u64,
+    follow: Option<Duration>,
+) -> Result<()> {
+    let headers = opts.headers;
+    let fetch = |from: u64, limit: usize| Command::Fetch {
+        topic: topic.to_string(),
+        offset: Some(from),
+        limit,
+        opts: opts.clone(),
+    };
+
+    // пустой FETCH - только границы лога
+    let (_, r) = conn.call(&fetch(0, 0))?;
+    let Response::FetchEnd {
+        high_watermark,
+        log_start,
+        ..
+    } = r
+    else {
+        return Err(format!("unexpected reply: {}", r).into());
+    };
+    let mut from = high_watermark.saturating_sub(n).max(log_start);
+
+    loop {
+        let (lines, r) = conn.call(&fetch(from, TAIL_PAGE))?;
+        print_records(printer, topic, &lines, headers)?;
+        std::io::stdout().flush()?;
+
+        let Response::FetchEnd {
+            next,
+            high_watermark,
+            ..
+        } = r
+        else {
+            return Err(format!("unexpected reply: {}", r).into());
+        };
+        from = next;
+
+        if from >= high_watermark {
+            let Some(interval) = follow else {
+                return Ok(());
+            };
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TopicOut<'a> {
+    topic: &'a str,
+    kind: &'a str,
+}
+
+fn topics(conn: &mut Conn, format: Format) -> Result<()> {
+    let (lines, _) = conn.call(&Command::Topics)?;
+    if format == Format::Table {
+        println!("{:<32}  KIND", "TOPIC");
+    }
+    for line in lines {
+        let (topic, kind) = line.split_once('\t').unwrap_or((&line, "-"));
+        match format {
+            Format::Raw => println!("{}", topic),
+            Format::Json => println!("{}", serde_json::to_string(&TopicOut { topic, kind })?),
+            Format::Table => println!("{:<32}  {}", topic, kind),
+        }
+    }
+    Ok(())
+}
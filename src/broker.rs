@@ -0,0 +1,254 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc::WeakSender, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::client::{ClientError, Fetched, Offset};
+use crate::config::{AppConfig, RuntimeConfig};
+use crate::protocol::{ErrorCode, RecordLine, is_valid_topic};
+use crate::queue::{FetchError, Request};
+use crate::service::Service;
+use crate::stats::Stats;
+
+/// Брокер внутри чужого процесса: интеграционные тесты, sidecar.
+pub struct Broker;
+
+impl Broker {
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder::default()
+    }
+}
+
+pub struct BrokerBuilder {
+    conf: AppConfig,
+}
+
+impl Default for BrokerBuilder {
+    fn default() -> Self {
+        BrokerBuilder {
+            conf: AppConfig {
+                // встроенному брокеру - любой свободный порт на loopback
+                bind_addr: "127.0.0.1:0".to_string(),
+                ..AppConfig::default()
+            },
+        }
+    }
+}
+
+impl BrokerBuilder {
+    pub fn data_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.conf.data_dir = dir.as_ref().to_string_lossy().into_owned();
+        self
+    }
+
+    /// `host:port`; порт 0 - выбрать свободный, см. `BrokerHandle::local_addr`.
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.conf.bind_addr = addr.into();
+        self
+    }
+
+    pub fn node_id(mut self, node_id: impl Into<String>) -> Self {
+        self.conf.node_id = node_id.into();
+        self
+    }
+
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.conf.queue_capacity = capacity;
+        self
+    }
+
+    /// Лимиты, настройки топиков по умолчанию и прочее, что меняется на лету.
+    pub fn runtime(mut self, runtime: RuntimeConfig) -> Self {
+        self.conf.runtime = runtime;
+        self
+    }
+
+    /// Проверяет настройки, занимает порт и запускает worker; соединения принимаются в фоне.
+    pub async fn start(self) -> Result<BrokerHandle, Box<dyn std::error::Error>> {
+        self.conf.validate()?;
+        self.conf.check_data_dir()?;
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (settings, settings_rx) = watch::channel(self.conf.runtime.clone());
+
+        let service = Service::new(&self.conf, settings_rx);
+        let listener = service.bind().await?;
+        let local_addr = listener.local_addr()?;
+        let core = service.spawn_core();
+
+        // слабая ссылка: иначе handle не даст worker-у завершиться при остановке
+        let tx = core.tx.downgrade();
+        let stats = core.stats.clone();
+        let task = tokio::spawn(async move { service.run(listener, core, &shutdown_rx).await });
+
+        Ok(BrokerHandle {
+            local_addr,
+            shutdown,
+            settings,
+            tx,
+            stats,
+            task,
+        })
+    }
+}
+
+/// Запущенный брокер. Остановка - `shutdown()` или drop handle.
+pub struct BrokerHandle {
+    local_addr: SocketAddr,
+    shutdown: watch::Sender<bool>,
+    settings: watch::Sender<RuntimeConfig>,
+    tx: WeakSender<Request>,
+    stats: Arc<Stats>,
+    task: JoinHandle<()>,
+}
+
+impl BrokerHandle {
+    /// Адрес, на котором брокер принимает соединения (с выбранным портом при bind на 0).
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// То же, что SIGHUP у отдельного процесса: новые лимиты и настройки топиков.
+    pub fn reload(&self, runtime: RuntimeConfig) {
+        self.settings.send_replace(runtime);
+    }
+
+    /// Остановка как по SIGTERM: drain соединений, затем worker досинхронизирует WAL.
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+        let _ = (&mut self.task).await;
+    }
+
+    /// Запись в обход TCP; возвращает offset сообщения.
+    pub async fn publish(&self, topic: &str, payload: &str) -> Result<Offset, ClientError> {
+        let (first, _) = self.publish_batch(topic, &[payload]).await?;
+        Ok(first)
+    }
+
+    pub async fn publish_batch(
+        &self,
+        topic: &str,
+        payloads: &[&str],
+    ) -> Result<(Offset, Offset), ClientError> {
+        // как MPUB по TCP: имя топика допустимо, пачка не пустая, каждый payload - одна строка
+        if !is_valid_topic(topic) {
+            return Err(bad_topic());
+        }
+        if payloads.is_empty() {
+            let detail = "batch must not be empty";
+            return Err(ClientError::Broker(ErrorCode::BadRequest, detail.into()));
+        }
+        if payloads.iter().any(|p| p.contains(['\n', '\r'])) {
+            let detail = "payload must not contain line breaks";
+            return Err(ClientError::Broker(ErrorCode::BadRequest, detail.into()));
+        }
+        let limits = self.settings.borrow().limits;
+        if payloads.len() > limits.max_batch_records
+            || payloads.iter().any(|p| p.len() > limits.max_msg_bytes)
+        {
+            let detail = "batch exceeds max_batch_records or max_msg_bytes";
+            return Err(ClientError::Broker(ErrorCode::TooLarge, detail.into()));
+        }
+
+        let tx = self.tx.upgrade().ok_or(ClientError::Closed)?;
+        let (committed, committed_rx) = oneshot::channel();
+        let records = payloads
+            .iter()
+            .map(|p| (self.stats.new_id(), p.to_string()))
+            .collect();
+        let req = Request::ProduceBatch {
+            topic: topic.to_string(),
+            prio: 0,
+            records,
+            committed,
+        };
+        tx.send(req).await.map_err(|_| ClientError::Closed)?;
+        committed_rx
+            .await
+            .map_err(|_| ClientError::Broker(ErrorCode::Wal, "write to wal failed".into()))?
+            .map_err(|_| {
+                let detail = "memory topics have no priority levels".into();
+                ClientError::Broker(ErrorCode::Conflict, detail)
+            })
+    }
+
+    /// Чтение в обход TCP; страница того же вида, что у `Client::fetch`.
+    pub async fn fetch(
+        &self,
+        topic: &str,
+        offset: Offset,
+        limit: usize,
+    ) -> Result<Fetched, ClientError> {
+        if !is_valid_topic(topic) {
+            return Err(bad_topic());
+        }
+        let limits = self.settings.borrow().limits;
+        let tx = self.tx.upgrade().ok_or(ClientError::Closed)?;
+        let (reply, reply_rx) = oneshot::channel();
+        let req = Request::Fetch {
+            topic: topic.to_string(),
+            from: Some(offset),
+            // те же пределы, что у FETCH по TCP
+            limit: limit.min(limits.max_batch_records),
+            max_bytes: limits.max_fetch_bytes,
+            prio: 0,
+            group: None,
+            reset: None,
+            reply,
+        };
+        tx.send(req).await.map_err(|_| ClientError::Closed)?;
+
+        let fetched = match reply_rx.await {
+            Ok(Ok(v)) => v,
+            Ok(Err(FetchError::UnknownTopic)) => {
+                let detail = "topic does not exist".into();
+                return Err(ClientError::Broker(ErrorCode::UnknownTopic, detail));
+            }
+            Ok(Err(FetchError::OffsetOutOfRange)) => {
+                let detail = "offset is outside the log".into();
+                return Err(ClientError::Broker(ErrorCode::OffsetOutOfRange, detail));
+            }
+            Ok(Err(FetchError::MemoryTopic)) => {
+                let detail = "memory topics have no priority levels".into();
+                return Err(ClientError::Broker(ErrorCode::Conflict, detail));
+            }
+            // worker остановился, пока ждали ответ
+            Err(_) => return Err(ClientError::Closed),
+        };
+
+        // как в FETCH по TCP: пустая страница начинается не раньше начала лога
+        let next = fetched
+            .records
+            .last()
+            .map(|e| e.offset + 1)
+            .unwrap_or(fetched.from.max(fetched.start_offset));
+        let records = fetched
+            .records
+            .into_iter()
+            .map(|e| RecordLine {
+                offset: e.offset,
+                id: e.id,
+                headers: e.headers,
+                payload: e.payload,
+            })
+            .collect();
+        Ok(Fetched {
+            records,
+            next,
+            high_watermark: fetched.next_offset,
+            log_start: fetched.start_offset,
+        })
+    }
+}
+
+fn bad_topic() -> ClientError {
+    ClientError::Broker(ErrorCode::BadRequest, "bad topic name".into())
+}
+
+impl Drop for BrokerHandle {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(true);
+    }
+}
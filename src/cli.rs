@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+
+use crate::config::ServeArgs;
+use crate::topic;
+use crate::wal::Wal;
+
+// коды выхода; 2 - ошибка в аргументах, его возвращает clap
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_CORRUPT: u8 = 3;
+
+#[derive(Parser)]
+#[command(version, about = "samovaroff message broker")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    #[command(about = "Run the broker (default)")]
+    Serve(Box<ServeArgs>),
+    #[command(subcommand, about = "Offline WAL tools")]
+    Wal(WalCommand),
+    #[command(subcommand, about = "Offline topic tools")]
+    Topics(TopicsCommand),
+}
+
+#[derive(Subcommand)]
+pub enum WalCommand {
+    #[command(about = "Dump segments and records of a WAL directory")]
+    Inspect { dir: PathBuf },
+    #[command(about = "Check WAL segments without modifying them (exit 3 if damaged)")]
+    Verify { dir: PathBuf },
+}
+
+#[derive(Subcommand)]
+pub enum TopicsCommand {
+    #[command(about = "List topics and their offsets in a data dir")]
+    List {
+        #[arg(long, env = "DATA_DIR", default_value = "./data")]
+        data_dir: String,
+    },
+}
+
+/// Офлайн-команды работают с файлами напрямую, брокер при этом лучше остановить.
+pub fn wal(cmd: WalCommand) -> ExitCode {
+    let res = match cmd {
+        WalCommand::Inspect { dir } => inspect(&dir).map_err(|e| with_path(&dir, e)),
+        WalCommand::Verify { dir } => verify(&dir).map_err(|e| with_path(&dir, e)),
+    };
+    exit_code(res)
+}
+
+pub fn topics(cmd: TopicsCommand) -> ExitCode {
+    let res = match cmd {
+        TopicsCommand::List { data_dir } => {
+            list_topics(&data_dir).map_err(|e| with_path(Path::new(&data_dir), e))
+        }
+    };
+    exit_code(res)
+}
+
+fn with_path(path: &Path, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+// Ok(false) - данные прочитаны, но повреждены
+fn exit_code(res: std::io::Result<bool>) -> ExitCode {
+    match res {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(EXIT_CORRUPT),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn inspect(dir: &Path) -> std::io::Result<bool> {
+    let mut clean = true;
+    for path in Wal::segment_files(dir)? {
+        let (records, bad_line) = Wal::read_raw(&path)?;
+        println!("# {} records={}", path.display(), records.len());
+        // <offset>\t<id>\t<meta|->\t<payload>
+        for r in records {
+            let meta = if r.meta.is_empty() { "-" } else { &r.meta };
+            println!("{}\t{}\t{}\t{}", r.offset, r.id, meta, r.payload);
+        }
+        if let Some(line) = bad_line {
+            println!("# unreadable line {}", line);
+            clean = false;
+        }
+    }
+    Ok(clean)
+}
+
+fn verify(dir: &Path) -> std::io::Result<bool> {
+    let checks = Wal::verify(dir)?;
+    if checks.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no wal files",
+        ));
+    }
+
+    let mut clean = true;
+    for c in checks {
+        match c.error {
+            None => println!("ok\t{}\t{}..{}", c.path.display(), c.start, c.next),
+            Some(e) => {
+                println!(
+                    "damaged\t{}\t{}..{}\t{}",
+                    c.path.display(),
+                    c.start,
+                    c.next,
+                    e
+                );
+                clean = false;
+            }
+        }
+    }
+    Ok(clean)
+}
+
+fn list_topics(data_dir: &str) -> std::io::Result<bool> {
+    let mut clean = true;
+    println!("TOPIC\tPRIO\tSTART\tNEXT");
+    for name in topic::list_topics(data_dir)? {
+        for (prio, dir) in topic::level_dirs(&Path::new(data_dir).join(&name))? {
+            let checks = Wal::verify(&dir)?;
+            let (Some(first), Some(last)) = (checks.first(), checks.last()) else {
+                continue;
+            };
+            let damaged = checks.iter().any(|c| c.error.is_some());
+            clean &= !damaged;
+            println!(
+                "{}\t{}\t{}\t{}{}",
+                name,
+                prio,
+                first.start,
+                last.next,
+                if damaged { "\tdamaged" } else { "" }
+            );
+        }
+    }
+    Ok(clean)
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use crate::protocol::{
+    Backpressure, Command, ErrorCode, Feature, FetchOpts, Hello, PROTOCOL_VERSION, RecordLine,
+    Response, split_id,
+};
+
+pub type Offset = u64;
+
+/// Параметры клиента: переподключение и конвейер запросов.
+#[derive(Clone)]
+pub struct ClientOpts {
+    // пауза перед первой повторной попыткой, дальше удваивается до max_backoff
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // после стольких неудачных попыток подряд запрос завершается ошибкой
+    pub reconnect_attempts: u32,
+    // сколько запросов может ждать ответа на одном соединении
+    pub max_in_flight: usize,
+    // как часто subscribe спрашивает новые записи, дойдя до конца лога
+    pub poll_interval: Duration,
+    // политика соединения при заполненной очереди брокера; None - как настроен брокер
+    pub backpressure: Option<Backpressure>,
+    // имя клиента в HELLO, попадает в лог брокера
+    pub name: String,
+}
+
+impl Default for ClientOpts {
+    fn default() -> Self {
+        ClientOpts {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            reconnect_attempts: 10,
+            max_in_flight: 128,
+            poll_interval: Duration::from_millis(200),
+            backpressure: None,
+            name: "samovaroff-client".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    // не удалось (пере)подключиться
+    Io(std::io::Error),
+    // соединение оборвалось, пока запрос ждал ответа; запрос мог быть выполнен
+    Disconnected,
+    // клиент закрыт
+    Closed,
+    // брокер ответил ошибкой: код и пояснение
+    Broker(ErrorCode, String),
+    // ответ не того вида, что ждали на команду
+    Unexpected(Response),
+    // запрос не отправлен: его нельзя записать в строковый протокол
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "connect: {}", e),
+            ClientError::Disconnected => write!(f, "connection lost"),
+            ClientError::Closed => write!(f, "client closed"),
+            ClientError::Broker(code, detail) => write!(f, "broker: {}: {}", code, detail),
+            ClientError::Unexpected(r) => write!(f, "unexpected reply: {}", r),
+            ClientError::Invalid(why) => write!(f, "invalid request: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl ClientError {
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Broker(code, _) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Можно ли повторить тот же запрос. После `Disconnected` запись могла и пройти.
+    pub fn retriable(&self) -> bool {
+        match self {
+            ClientError::Io(_) | ClientError::Disconnected => true,
+            ClientError::Broker(code, _) => code.retriable(),
+            ClientError::Closed | ClientError::Unexpected(_) | ClientError::Invalid(_) => false,
+        }
+    }
+}
+
+/// Страница FETCH.
+pub struct Fetched {
+    pub records: Vec<RecordLine>,
+    pub next: Offset,
+    pub high_watermark: Offset,
+    pub log_start: Offset,
+}
+
+// строки данных ответа и итоговая строка
+type Reply = Result<(Vec<String>, Response), ClientError>;
+
+struct Call {
+    // команда и, для MPUB, строки payload-ов
+    lines: String,
+    reply: oneshot::Sender<Reply>,
+}
+
+/// Асинхронный клиент брокера. Клоны делят одно соединение: запросы из разных задач
+/// уходят конвейером, не дожидаясь ответов на предыдущие.
+#[derive(Clone)]
+pub struct Client {
+    calls: mpsc::Sender<Call>,
+    opts: ClientOpts,
+    // ответ на HELLO текущего соединения; None - брокер без рукопожатия
+    server: watch::Receiver<Option<Hello>>,
+}
+
+impl Client {
+    pub async fn connect(addr: &str) -> Result<Self, ClientError> {
+        Self::connect_with(addr, ClientOpts::default()).await
+    }
+
+    /// Первое подключение - сразу, без повторов: ошибка в адресе видна на старте.
+    pub async fn connect_with(addr: &str, opts: ClientOpts) -> Result<Self, ClientError> {
+        let (stream, hello) = open(addr, &opts).await.map_err(ClientError::Io)?;
+        let (tx, rx) = mpsc::channel(opts.max_in_flight);
+        let (server_tx, server) = watch::channel(hello);
+        let conn = Connection {
+            addr: addr.to_string(),
+            opts: opts.clone(),
+            server: server_tx,
+        };
+        tokio::spawn(conn.run(stream, rx));
+        Ok(Client {
+            calls: tx,
+            opts,
+            server,
+        })
+    }
+
+    /// Что брокер ответил на HELLO при последнем подключении: версия, node_id, возможности.
+    pub fn server(&self) -> Option<Hello> {
+        self.server.borrow().clone()
+    }
+
+    async fn call(&self, lines: String) -> Reply {
+        let (reply, rx) = oneshot::channel();
+        self.calls
+            .send(Call { lines, reply })
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        match rx.await.map_err(|_| ClientError::Closed)?? {
+            (_, Response::Err { code, detail }) => Err(ClientError::Broker(code, detail.into())),
+            reply => Ok(reply),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<(), ClientError> {
+        self.call(format!("{}\n", Command::Ping)).await?;
+        Ok(())
+    }
+
+    /// Запись одного сообщения; возвращает его offset.
+    pub async fn publish(&self, topic: &str, payload: &str) -> Result<Offset, ClientError> {
+        let (first, _) = self.publish_batch(topic, &[payload]).await?;
+        Ok(first)
+    }
+
+    /// Пачка сообщений одним MPUB: offset-ы первого и последнего.
+    pub async fn publish_batch(
+        &self,
+        topic: &str,
+        payloads: &[&str],
+    ) -> Result<(Offset, Offset), ClientError> {
+        // перевод строки в payload брокер прочел бы как новую команду
+        if payloads.is_empty() {
+            return Err(ClientError::Invalid("batch must not be empty"));
+        }
+        if payloads.iter().any(|p| p.contains(['\n', '\r'])) {
+            return Err(ClientError::Invalid("payload must not contain line breaks"));
+        }
+        let cmd = Command::MPub {
+            topic: topic.to_string(),
+            count: payloads.len(),
+            prio: 0,
+        };
+        let mut lines = format!("{}\n", cmd);
+        for p in payloads {
+            lines.push_str(p);
+            lines.push('\n');
+        }
+        match self.call(lines).await? {
+            (_, Response::AckRange(first, last)) => Ok((first, last)),
+            (_, r) => Err(ClientError::Unexpected(r)),
+        }
+    }
+
+    pub async fn fetch(
+        &self,
+        topic: &str,
+        offset: Offset,
+        limit: usize,
+    ) -> Result<Fetched, ClientError> {
+        let cmd = Command::Fetch {
+            topic: topic.to_string(),
+            offset: Some(offset),
+            limit,
+            opts: FetchOpts {
+                headers: true,
+                prio: 0,
+                max_bytes: None,
+                group: None,
+                reset: None,
+            },
+        };
+        let (data, r) = self.call(format!("{}\n", cmd)).await?;
+        let Response::FetchEnd {
+            next,
+            high_watermark,
+            log_start,
+        } = r
+        else {
+            return Err(ClientError::Unexpected(r));
+        };
+        let records = data
+            .iter()
+            .filter_map(|line| RecordLine::parse(line, true))
+            .collect();
+        Ok(Fetched {
+            records,
+            next,
+            high_watermark,
+            log_start,
+        })
+    }
+
+    /// Поток записей топика начиная с `from`; дойдя до конца лога, ждет новые.
+    /// Обрыв соединения поток переживает и продолжает с последней выданной записи.
+    pub fn subscribe(
+        &self,
+        topic: &str,
+        from: Offset,
+    ) -> impl Stream<Item = Result<RecordLine, ClientError>> + Unpin + use<> {
+        let (tx, rx) = mpsc::channel(self.opts.max_in_flight);
+        tokio::spawn(subscription(self.clone(), topic.to_string(), from, tx));
+        ReceiverStream::new(rx)
+    }
+}
+
+// размер страницы FETCH у subscribe
+const SUBSCRIBE_PAGE: usize = 500;
+
+async fn subscription(
+    client: Client,
+    topic: String,
+    mut from: Offset,
+    tx: mpsc::Sender<Result<RecordLine, ClientError>>,
+) {
+    loop {
+        let page = match client.fetch(&topic, from, SUBSCRIBE_PAGE).await {
+            Ok(page) => page,
+            // запрос потерян вместе с соединением, просто повторяем
+            Err(ClientError::Disconnected) => continue,
+            // брокер перегружен или завершается - повторяем после паузы
+            Err(e @ ClientError::Broker(..)) if e.retriable() => {
+                debug!(topic = %topic, error = %e, "fetch failed, retrying");
+                tokio::time::sleep(client.opts.poll_interval).await;
+                continue;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+        let caught_up = page.next >= page.high_watermark;
+        from = page.next;
+        for r in page.records {
+            if tx.send(Ok(r)).await.is_err() {
+                return;
+            }
+        }
+        if caught_up {
+            tokio::select! {
+                _ = tx.closed() => return,
+                _ = tokio::time::sleep(client.opts.poll_interval) => {}
+            }
+        }
+    }
+}
+
+// соединение живет в своей задаче; после обрыва переподключаемся при следующем запросе
+struct Connection {
+    addr: String,
+    opts: ClientOpts,
+    server: watch::Sender<Option<Hello>>,
+}
+
+impl Connection {
+    async fn run(self, stream: TcpStream, mut calls: mpsc::Receiver<Call>) {
+        let mut stream = Some(stream);
+        while let Some(call) = calls.recv().await {
+            let s = match stream.take() {
+                Some(s) => s,
+                None => match reconnect(&self.addr, &self.opts).await {
+                    Ok((s, hello)) => {
+                        // после рестарта брокер мог смениться версией или узлом
+                        self.server.send_replace(hello);
+                        s
+                    }
+                    Err(e) => {
+                        let _ = call.reply.send(Err(ClientError::Io(e)));
+                        continue;
+                    }
+                },
+            };
+            let ids = self
+                .server
+                .borrow()
+                .as_ref()
+                .is_some_and(|h| h.features.contains(&Feature::Ids));
+            serve_calls(s, call, &mut calls, self.opts.max_in_flight, ids).await;
+        }
+    }
+}
+
+async fn reconnect(addr: &str, opts: &ClientOpts) -> std::io::Result<(TcpStream, Option<Hello>)> {
+    let mut backoff = opts.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match open(addr, opts).await {
+            Ok(s) => {
+                debug!(addr, attempt, "reconnected");
+                return Ok(s);
+            }
+            Err(e) if attempt >= opts.reconnect_attempts => return Err(e),
+            Err(e) => {
+                debug!(addr, attempt, error = %e, backoff_ms = backoff.as_millis() as u64, "reconnect failed");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(opts.max_backoff);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// новое соединение сначала проходит HELLO и получает настройки сессии, потом уже запросы
+async fn open(addr: &str, opts: &ClientOpts) -> std::io::Result<(TcpStream, Option<Hello>)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let hello = Command::Hello {
+        version: PROTOCOL_VERSION,
+        client: opts.name.clone(),
+        features: Feature::ALL.to_vec(),
+    };
+    let hello = match handshake(&mut stream, &hello).await? {
+        Response::Hello(h) => Some(h),
+        // брокер старше рукопожатия - работаем как раньше
+        Response::Err {
+            code: ErrorCode::UnknownCommand,
+            ..
+        } => None,
+        r => return Err(std::io::Error::other(format!("hello rejected: {}", r))),
+    };
+    if let Some(policy) = opts.backpressure {
+        let r = handshake(&mut stream, &Command::Backpressure(policy)).await?;
+        if r != Response::Ok {
+            return Err(std::io::Error::other(format!(
+                "backpressure rejected: {}",
+                r
+            )));
+        }
+    }
+    Ok((stream, hello))
+}
+
+// одна команда и ее ответ до начала конвейера
+async fn handshake(stream: &mut TcpStream, cmd: &Command) -> std::io::Result<Response> {
+    stream.write_all(format!("{}\n", cmd).as_bytes()).await?;
+    // до ответа брокер больше ничего не пишет, так что буфер можно бросить
+    let mut line = String::new();
+    BufReader::new(&mut *stream).read_line(&mut line).await?;
+    Response::parse(&line)
+        .ok_or_else(|| std::io::Error::other(format!("unexpected reply: {}", line.trim_end())))
+}
+
+// Запросы пишутся сразу. С `ids` брокер выполняет их параллельно и отвечает в любом
+// порядке, с тем же id; без - по одному и в порядке запросов.
+// Возвращается, когда соединение оборвалось или клиент закрыт.
+async fn serve_calls(
+    stream: TcpStream,
+    first: Call,
+    calls: &mut mpsc::Receiver<Call>,
+    max_in_flight: usize,
+    ids: bool,
+) {
+    let (r, mut w) = stream.into_split();
+    let mut lines = BufReader::new(r).lines();
+    // по id запроса; без ids ответ всегда на самый ранний
+    let mut pending: BTreeMap<u64, oneshot::Sender<Reply>> = BTreeMap::new();
+    let mut next_id: u64 = 0;
+    let mut data = Vec::new();
+    let mut next = Some(first);
+
+    loop {
+        if let Some(call) = next.take() {
+            next_id += 1;
+            let lines = if ids {
+                // id только у строки команды, строки payload-ов MPUB идут как есть
+                format!("#{} {}", next_id, call.lines)
+            } else {
+                call.lines
+            };
+            if w.write_all(lines.as_bytes()).await.is_err() {
+                let _ = call.reply.send(Err(ClientError::Disconnected));
+                break;
+            }
+            pending.insert(next_id, call.reply);
+        }
+
+        tokio::select! {
+            call = calls.recv(), if pending.len() < max_in_flight => match call {
+                Some(call) => next = Some(call),
+                None => break,
+            },
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else {
+                    break;
+                };
+                let (id, r) = match split_id(&line) {
+                    Some((id, rest)) => (Some(id), Response::parse(rest)),
+                    None => (None, Response::parse(&line)),
+                };
+                let Some(r) = r else {
+                    data.push(line);
+                    continue;
+                };
+                let reply = match id {
+                    Some(id) => pending.remove(&id),
+                    None if !ids => pending.pop_first().map(|(_, reply)| reply),
+                    None => None,
+                };
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(Ok((std::mem::take(&mut data), r)));
+                    }
+                    // брокер закрывает простаивающее соединение с ERR TIMEOUT
+                    None => {
+                        debug!(reply = %r, "unsolicited reply, reconnecting");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    for reply in pending.into_values() {
+        let _ = reply.send(Err(ClientError::Disconnected));
+    }
+}
@@ -0,0 +1,11 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Текущее время в unix-миллисекундах.
+/// Логика, завязанная на время, принимает `now_ms` параметром -
+/// так ее можно гонять с подставными часами.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
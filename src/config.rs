@@ -1,10 +1,147 @@
-use tracing::{debug, error, info};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use clap::Args;
+use serde::{Deserialize, Deserializer};
+use tracing::{debug, info};
+
+use crate::protocol::Backpressure;
+use crate::topic::TopicDefaults;
+
+// путь к файлу конфига, если не задан --config
+const CONFIG_ENV: &str = "SAMOVAROFF_CONFIG";
+
+/// Аргументы `serve`: путь к конфигу и флаги, перекрывающие файл и окружение.
+#[derive(Default, Args)]
+pub struct ServeArgs {
+    #[arg(
+        long,
+        short,
+        value_name = "PATH",
+        help = "TOML config file [env: SAMOVAROFF_CONFIG]"
+    )]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: ConfigLayer,
+}
+
+/// Один слой настроек: файл, переменные окружения или флаги командной строки.
+/// Незаданные поля берутся из предыдущего слоя.
+#[derive(Default, Clone, Deserialize, Args)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    #[arg(long)]
+    pub node_id: Option<String>,
+    #[arg(long)]
+    pub bind_addr: Option<String>,
+    #[arg(long)]
+    pub data_dir: Option<String>,
+    #[arg(long)]
+    pub queue_capacity: Option<usize>,
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    #[arg(long)]
+    pub drain_timeout_ms: Option<u64>,
+    #[arg(long)]
+    pub max_msg_bytes: Option<usize>,
+    #[arg(long)]
+    pub max_batch_records: Option<usize>,
+    #[arg(long)]
+    pub max_fetch_bytes: Option<usize>,
+    #[arg(long)]
+    pub read_timeout_ms: Option<u64>,
+    #[arg(long)]
+    pub default_visibility_ms: Option<u64>,
+    #[arg(long)]
+    pub max_request_timeout_ms: Option<u64>,
+    #[arg(long)]
+    pub default_session_timeout_ms: Option<u64>,
+    #[arg(long, value_name = "reject|block:<ms>|pause")]
+    pub backpressure: Option<Backpressure>,
+    #[arg(long)]
+    pub max_in_flight: Option<usize>,
+    #[serde(default)]
+    #[command(flatten)]
+    pub topic_defaults: TopicLayer,
+}
+
+/// Секция `[topic_defaults]`.
+#[derive(Default, Clone, Deserialize, Args)]
+#[serde(deny_unknown_fields)]
+pub struct TopicLayer {
+    #[arg(long = "topic-ttl-ms")]
+    pub ttl_ms: Option<u64>,
+    #[arg(long = "topic-max-attempts")]
+    pub max_attempts: Option<u32>,
+    #[arg(long = "topic-mem-capacity")]
+    pub mem_capacity: Option<usize>,
+    #[arg(long)]
+    pub max_wal_bytes: Option<u64>,
+}
+
+/// Ограничения на запросы клиентов.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_msg_bytes: usize,
+    pub max_batch_records: usize,
+    // предел размера одного ответа FETCH, MAXBYTES клиента его только уменьшает
+    pub max_fetch_bytes: usize,
+    pub read_timeout_ms: u64,
+    pub default_visibility_ms: u64,
+    pub max_request_timeout_ms: u64,
+    pub default_session_timeout_ms: u64,
+    // что делать с записью при заполненной очереди, если соединение не выбрало свое
+    pub backpressure: Backpressure,
+    // сколько запросов с id одно соединение может держать в работе одновременно
+    pub max_in_flight: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_msg_bytes: 64 * 1024, // 64KB
+            max_batch_records: 10_000,
+            max_fetch_bytes: 1024 * 1024, // 1MB
+            read_timeout_ms: 30_000,
+            default_visibility_ms: 30_000,
+            max_request_timeout_ms: 60_000,
+            default_session_timeout_ms: 10_000,
+            backpressure: Backpressure::Reject,
+            max_in_flight: 64,
+        }
+    }
+}
+
+/// Настройки, которые можно поменять без рестарта (SIGHUP).
+#[derive(Clone)]
+pub struct RuntimeConfig {
+    pub max_connections: usize,
+    // сколько ждать завершения запросов клиентов при остановке
+    pub drain_timeout_ms: u64,
+    pub limits: Limits,
+    pub topic_defaults: TopicDefaults,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            max_connections: 256,
+            drain_timeout_ms: 30_000,
+            limits: Limits::default(),
+            topic_defaults: TopicDefaults::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct AppConfig {
     pub node_id: String,
     pub bind_addr: String,
     pub data_dir: String,
-    pub max_connections: usize,
+    // размер очереди запросов к worker
+    pub queue_capacity: usize,
+    pub runtime: RuntimeConfig,
 }
 
 impl Default for AppConfig {
@@ -13,37 +150,259 @@ impl Default for AppConfig {
             node_id: "node-1".to_string(),
             bind_addr: "[::]:7001".to_string(),
             data_dir: "./data".to_string(),
-            max_connections: 256,
+            queue_capacity: 100,
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env {
+        var: &'static str,
+        value: String,
+    },
+    Invalid {
+        key: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "config {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "config {}: {}", path.display(), e),
+            ConfigError::Env { var, value } => write!(f, "env {}={:?}: invalid value", var, value),
+            ConfigError::Invalid { key, reason } => write!(f, "config {}: {}", key, reason),
         }
     }
 }
 
+impl std::error::Error for ConfigError {}
+
 impl AppConfig {
-    pub fn load() -> Self {
+    /// Настройки по умолчанию, поверх них файл, переменные окружения и флаги.
+    pub fn load(args: &ServeArgs) -> Result<Self, ConfigError> {
         let mut c = Self::default();
-        c.bind_addr = std::env::var("BIND_ADDR").unwrap_or(c.bind_addr);
-        c.data_dir = std::env::var("DATA_DIR").unwrap_or(c.data_dir);
-        c.node_id = std::env::var("NODE_ID").unwrap_or(c.node_id);
-        c.max_connections = std::env::var("MAX_CONNECTIONS")
-            .ok()
-            .and_then(|v| v.parse::<usize>().ok())
-            .unwrap_or(c.max_connections);
-
-        if let Err(e) = Self::check_data_dir(&c) {
-            error!(error = %e, "failed to create data dir");
-            std::process::exit(1);
+
+        let path = args
+            .config
+            .clone()
+            .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+        if let Some(path) = path {
+            c.apply(read_file(&path)?);
         }
-        c
+        c.apply(env_layer()?);
+        c.apply(args.overrides.clone());
+
+        c.validate()?;
+        Ok(c)
+    }
+
+    fn apply(&mut self, l: ConfigLayer) {
+        let r = &mut self.runtime;
+        let t = &mut r.topic_defaults;
+        set(&mut self.node_id, l.node_id);
+        set(&mut self.bind_addr, l.bind_addr);
+        set(&mut self.data_dir, l.data_dir);
+        set(&mut self.queue_capacity, l.queue_capacity);
+        set(&mut r.max_connections, l.max_connections);
+        set(&mut r.drain_timeout_ms, l.drain_timeout_ms);
+        set(&mut r.limits.max_msg_bytes, l.max_msg_bytes);
+        set(&mut r.limits.max_batch_records, l.max_batch_records);
+        set(&mut r.limits.max_fetch_bytes, l.max_fetch_bytes);
+        set(&mut r.limits.read_timeout_ms, l.read_timeout_ms);
+        set(&mut r.limits.default_visibility_ms, l.default_visibility_ms);
+        set(
+            &mut r.limits.max_request_timeout_ms,
+            l.max_request_timeout_ms,
+        );
+        set(
+            &mut r.limits.default_session_timeout_ms,
+            l.default_session_timeout_ms,
+        );
+        set(&mut r.limits.backpressure, l.backpressure);
+        set(&mut r.limits.max_in_flight, l.max_in_flight);
+        t.ttl_ms = l.topic_defaults.ttl_ms.or(t.ttl_ms);
+        t.max_attempts = l.topic_defaults.max_attempts.or(t.max_attempts);
+        set(&mut t.mem_capacity, l.topic_defaults.mem_capacity);
+        set(&mut t.max_wal_bytes, l.topic_defaults.max_wal_bytes);
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let r = &self.runtime;
+        let checks = [
+            ("node_id", self.node_id.is_empty(), "must not be empty"),
+            ("data_dir", self.data_dir.is_empty(), "must not be empty"),
+            (
+                "bind_addr",
+                self.bind_addr
+                    .rsplit_once(':')
+                    .and_then(|(_, port)| port.parse::<u16>().ok())
+                    .is_none(),
+                "expected host:port",
+            ),
+            (
+                "queue_capacity",
+                self.queue_capacity == 0,
+                "must be positive",
+            ),
+            (
+                "max_connections",
+                r.max_connections == 0,
+                "must be positive",
+            ),
+            (
+                "max_msg_bytes",
+                r.limits.max_msg_bytes == 0,
+                "must be positive",
+            ),
+            (
+                "max_batch_records",
+                r.limits.max_batch_records == 0,
+                "must be positive",
+            ),
+            (
+                "max_fetch_bytes",
+                r.limits.max_fetch_bytes < r.limits.max_msg_bytes,
+                "must not be less than max_msg_bytes",
+            ),
+            (
+                "read_timeout_ms",
+                r.limits.read_timeout_ms == 0,
+                "must be positive",
+            ),
+            (
+                "default_visibility_ms",
+                r.limits.default_visibility_ms == 0,
+                "must be positive",
+            ),
+            (
+                "max_request_timeout_ms",
+                r.limits.max_request_timeout_ms == 0,
+                "must be positive",
+            ),
+            (
+                "default_session_timeout_ms",
+                r.limits.default_session_timeout_ms == 0,
+                "must be positive",
+            ),
+            (
+                "max_in_flight",
+                r.limits.max_in_flight == 0,
+                "must be positive",
+            ),
+            (
+                "topic_defaults.max_attempts",
+                r.topic_defaults.max_attempts == Some(0),
+                "must be positive",
+            ),
+            (
+                "topic_defaults.mem_capacity",
+                r.topic_defaults.mem_capacity == 0,
+                "must be positive",
+            ),
+            (
+                "topic_defaults.max_wal_bytes",
+                r.topic_defaults.max_wal_bytes < r.limits.max_msg_bytes as u64,
+                "must not be less than max_msg_bytes",
+            ),
+        ];
+        match checks.into_iter().find(|(_, bad, _)| *bad) {
+            Some((key, _, reason)) => Err(ConfigError::Invalid { key, reason }),
+            None => Ok(()),
+        }
+    }
+
+    /// Настройки, которые применяются только при старте: их изменение по SIGHUP игнорируется.
+    pub fn restart_required(&self, other: &AppConfig) -> Vec<&'static str> {
+        [
+            ("node_id", self.node_id != other.node_id),
+            ("bind_addr", self.bind_addr != other.bind_addr),
+            ("data_dir", self.data_dir != other.data_dir),
+            (
+                "queue_capacity",
+                self.queue_capacity != other.queue_capacity,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| key)
+        .collect()
     }
 
-    fn check_data_dir(config: &AppConfig) -> Result<(), std::io::Error> {
-        let dir = std::path::Path::new(config.data_dir.as_str());
+    pub fn check_data_dir(&self) -> Result<(), std::io::Error> {
+        let dir = std::path::Path::new(self.data_dir.as_str());
         if !dir.exists() {
             std::fs::create_dir_all(dir)?;
-            info!(data_dir = %config.data_dir, "data dir created");
+            info!(data_dir = %self.data_dir, "data dir created");
         } else {
-            debug!(data_dir = %config.data_dir, "data dir already exists");
+            debug!(data_dir = %self.data_dir, "data dir already exists");
         }
         Ok(())
     }
 }
+
+fn set<T>(dst: &mut T, v: Option<T>) {
+    if let Some(v) = v {
+        *dst = v;
+    }
+}
+
+fn read_file(path: &Path) -> Result<ConfigLayer, ConfigError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+fn env_layer() -> Result<ConfigLayer, ConfigError> {
+    Ok(ConfigLayer {
+        node_id: env("NODE_ID")?,
+        bind_addr: env("BIND_ADDR")?,
+        data_dir: env("DATA_DIR")?,
+        queue_capacity: env("QUEUE_CAPACITY")?,
+        max_connections: env("MAX_CONNECTIONS")?,
+        drain_timeout_ms: env("DRAIN_TIMEOUT_MS")?,
+        max_msg_bytes: env("MAX_MSG_BYTES")?,
+        max_batch_records: env("MAX_BATCH_RECORDS")?,
+        max_fetch_bytes: env("MAX_FETCH_BYTES")?,
+        read_timeout_ms: env("READ_TIMEOUT_MS")?,
+        default_visibility_ms: env("DEFAULT_VISIBILITY_MS")?,
+        max_request_timeout_ms: env("MAX_REQUEST_TIMEOUT_MS")?,
+        default_session_timeout_ms: env("DEFAULT_SESSION_TIMEOUT_MS")?,
+        backpressure: env("BACKPRESSURE")?,
+        max_in_flight: env("MAX_IN_FLIGHT")?,
+        topic_defaults: TopicLayer {
+            ttl_ms: env("TOPIC_TTL_MS")?,
+            max_attempts: env("TOPIC_MAX_ATTEMPTS")?,
+            mem_capacity: env("TOPIC_MEM_CAPACITY")?,
+            max_wal_bytes: env("MAX_WAL_BYTES")?,
+        },
+    })
+}
+
+// в файле - строкой, как и во флаге: `backpressure = "block:500"`
+impl<'de> Deserialize<'de> for Backpressure {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let v = String::deserialize(d)?;
+        v.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// незаданная переменная - None, заданная с мусором - ошибка
+fn env<T: FromStr>(var: &'static str) -> Result<Option<T>, ConfigError> {
+    match std::env::var(var) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Env { var, value }),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(ConfigError::Env {
+            var,
+            value: String::new(),
+        }),
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions, rename},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::protocol::ResetPolicy;
+
+// после стольких коммитов лог переписывается только с последними offset-ами
+const COMPACT_AFTER_COMMITS: usize = 1024;
+
+/// Позиция группы в логе: следующий offset, который группа будет читать.
+pub struct Committed {
+    pub offset: u64,
+    pub at_ms: u64,
+}
+
+// (группа, топик, уровень приоритета)
+pub type GroupKey = (String, String, u8);
+
+/// Закоммиченные offset-ы и настройки consumer-групп.
+/// Формат строк `__groups/offsets.log`:
+///   `O\t<group>\t<topic>\t<prio>\t<offset>\t<at_ms>` - группа закоммитила offset
+///   `R\t<group>\t<earliest|latest|error>` - политика сброса offset-а группы
+///   `E\t<epoch>` - номер запуска брокера, растет при каждом открытии
+/// Действует последняя строка по ключу.
+pub struct GroupOffsets {
+    file: File,
+    path: PathBuf,
+    offsets: BTreeMap<GroupKey, Committed>,
+    resets: BTreeMap<String, ResetPolicy>,
+    epoch: u64,
+    commits_since_compact: usize,
+}
+
+impl GroupOffsets {
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join("offsets.log");
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let mut g = GroupOffsets {
+            file,
+            path,
+            offsets: BTreeMap::new(),
+            resets: BTreeMap::new(),
+            epoch: 0,
+            commits_since_compact: 0,
+        };
+
+        g.recover()?;
+        // новый запуск - новая эпоха; пишется до того, как участники получат поколения
+        g.epoch += 1;
+        writeln!(g.file, "E\t{}", g.epoch)?;
+        g.file.sync_all()?;
+        Ok(g)
+    }
+
+    fn recover(&mut self) -> std::io::Result<()> {
+        let f = OpenOptions::new().read(true).open(&self.path)?;
+        let mut valid_end: u64 = 0;
+
+        for line in BufReader::new(f).lines() {
+            let Ok(line) = line else { break };
+            if let Some((key, c)) = parse_commit(&line) {
+                self.offsets.insert(key, c);
+            } else if let Some((group, policy)) = parse_reset(&line) {
+                self.resets.insert(group, policy);
+            } else if let Some(epoch) = line.strip_prefix("E\t").and_then(|v| v.parse().ok()) {
+                self.epoch = self.epoch.max(epoch);
+            } else {
+                break;
+            }
+            self.commits_since_compact += 1;
+            valid_end += (line.len() + 1) as u64;
+        }
+
+        // обрезаем битый хвост
+        self.file.set_len(valid_end)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    pub fn commit(
+        &mut self,
+        group: &str,
+        topic: &str,
+        prio: u8,
+        offset: u64,
+        now_ms: u64,
+    ) -> std::io::Result<()> {
+        let key = (group.to_string(), topic.to_string(), prio);
+        let c = Committed {
+            offset,
+            at_ms: now_ms,
+        };
+        writeln!(self.file, "{}", format_commit(&key, &c))?;
+        self.file.sync_all()?;
+
+        self.offsets.insert(key, c);
+        self.commits_since_compact += 1;
+        if self.commits_since_compact >= COMPACT_AFTER_COMMITS {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, group: &str, topic: &str, prio: u8) -> Option<&Committed> {
+        self.offsets
+            .get(&(group.to_string(), topic.to_string(), prio))
+    }
+
+    pub fn set_reset_policy(&mut self, group: &str, policy: ResetPolicy) -> std::io::Result<()> {
+        writeln!(self.file, "R\t{}\t{}", group, policy.as_str())?;
+        self.file.sync_all()?;
+        self.resets.insert(group.to_string(), policy);
+        Ok(())
+    }
+
+    pub fn reset_policy(&self, group: &str) -> Option<ResetPolicy> {
+        self.resets.get(group).copied()
+    }
+
+    /// Номер текущего запуска; у каждого следующего он больше.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Все offset-ы, или только offset-ы одной группы.
+    pub fn iter<'a>(
+        &'a self,
+        group: Option<&'a str>,
+    ) -> impl Iterator<Item = (&'a GroupKey, &'a Committed)> {
+        self.offsets
+            .iter()
+            .filter(move |((g, _, _), _)| group.is_none_or(|want| want == g))
+    }
+
+    fn compact(&mut self) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("log.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            for (key, c) in &self.offsets {
+                writeln!(f, "{}", format_commit(key, c))?;
+            }
+            for (group, policy) in &self.resets {
+                writeln!(f, "R\t{}\t{}", group, policy.as_str())?;
+            }
+            writeln!(f, "E\t{}", self.epoch)?;
+            f.sync_all()?;
+        }
+        rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.path)?;
+        self.commits_since_compact = 0;
+        Ok(())
+    }
+}
+
+fn parse_commit(line: &str) -> Option<(GroupKey, Committed)> {
+    let mut it = line.split('\t');
+    if it.next()? != "O" {
+        return None;
+    }
+    let group = it.next()?.to_string();
+    let topic = it.next()?.to_string();
+    let prio = it.next()?.parse::<u8>().ok()?;
+    let offset = it.next()?.parse::<u64>().ok()?;
+    let at_ms = it.next()?.parse::<u64>().ok()?;
+    if it.next().is_some() || group.is_empty() || topic.is_empty() {
+        return None;
+    }
+    Some(((group, topic, prio), Committed { offset, at_ms }))
+}
+
+fn parse_reset(line: &str) -> Option<(String, ResetPolicy)> {
+    let mut it = line.split('\t');
+    if it.next()? != "R" {
+        return None;
+    }
+    let group = it.next().filter(|g| !g.is_empty())?.to_string();
+    let policy = ResetPolicy::parse(it.next()?)?;
+    if it.next().is_some() {
+        return None;
+    }
+    Some((group, policy))
+}
+
+fn format_commit((group, topic, prio): &GroupKey, c: &Committed) -> String {
+    format!(
+        "O\t{}\t{}\t{}\t{}\t{}",
+        group, topic, prio, c.offset, c.at_ms
+    )
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::oneshot;
+
+use crate::clock;
+
+/// Эфемерные inbox-ы для REQUEST/REPLY.
+/// Живут только в памяти: ответ передается ждущему соединению напрямую,
+/// поэтому на диске после них ничего не остается.
+pub struct Inboxes {
+    prefix: String,
+    next: AtomicU64,
+    waiting: Mutex<HashMap<String, oneshot::Sender<String>>>,
+}
+
+impl Default for Inboxes {
+    fn default() -> Self {
+        Inboxes {
+            // время старта в префиксе, чтобы id не повторялись после рестарта
+            prefix: format!("{:x}", clock::now_ms()),
+            next: AtomicU64::new(0),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Inboxes {
+    /// Заводит inbox под новый correlation id.
+    pub fn open(&self) -> (String, oneshot::Receiver<String>) {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let corr_id = format!("{}-{}", self.prefix, n);
+        let (tx, rx) = oneshot::channel();
+        self.waiting
+            .lock()
+            .expect("inbox lock poisoned")
+            .insert(corr_id.clone(), tx);
+        (corr_id, rx)
+    }
+
+    pub fn close(&self, corr_id: &str) {
+        self.waiting
+            .lock()
+            .expect("inbox lock poisoned")
+            .remove(corr_id);
+    }
+
+    /// Отдает ответ ждущему запросу. `false` - никто не ждет (таймаут или чужой id).
+    pub fn deliver(&self, corr_id: &str, payload: String) -> bool {
+        let tx = self
+            .waiting
+            .lock()
+            .expect("inbox lock poisoned")
+            .remove(corr_id);
+        match tx {
+            Some(tx) => tx.send(payload).is_ok(),
+            None => false,
+        }
+    }
+}
@@ -1,19 +1,150 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, Lines};
 use tokio::net::TcpStream;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Sender};
 use tokio::sync::oneshot;
+use tokio::task::JoinSet;
 
-use crate::init::Shutdown;
-use crate::protocol::{Command, Response};
-use crate::queue::{EnqueueResult, Request, try_enqueue};
+use crate::clock;
+use crate::config::Limits;
+use crate::inbox::Inboxes;
+use crate::init::{Settings, Shutdown};
+use crate::membership::Assignment;
+use crate::protocol::{
+    Backpressure, Command, ErrorCode, Feature, FetchOpts, Hello, MsgRef, PROTOCOL_VERSION, PubOpts,
+    Response, SeekTarget, TopicOpts, format_headers, split_id,
+};
+use crate::queue::{
+    CommitResult, EnqueueResult, FetchError, MemoryTopic, ProduceOpts, QueueTx, Request,
+    SeekResult, TxRecord, enqueue, enqueue_batch,
+};
 use crate::stats::Stats;
 
-const MAX_MSG_BYTES: usize = 64 * 1024; // 64KB
-const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+const CORRELATION_ID_HEADER: &str = "correlation-id";
+
+// worker уже остановлен: брокер завершается
+fn unavailable() -> Response {
+    Response::err(ErrorCode::Unavailable, "broker is shutting down")
+}
+
+fn wal_failed() -> Response {
+    Response::err(ErrorCode::Wal, "write to wal failed")
+}
+
+fn queue_full() -> Response {
+    Response::err(ErrorCode::QueueFull, "request queue is full, retry later")
+}
+
+fn memory_topic(detail: &'static str) -> Response {
+    Response::err(ErrorCode::Conflict, detail)
+}
+
+fn unknown_topic() -> Response {
+    Response::err(ErrorCode::UnknownTopic, "topic does not exist")
+}
+
+fn unknown_member() -> Response {
+    Response::err(
+        ErrorCode::UnknownMember,
+        "member is not in the group, join again",
+    )
+}
+
+fn no_transaction() -> Response {
+    Response::err(ErrorCode::InvalidState, "no open transaction")
+}
+
+// транзакция принадлежит сессии, а запрос с id выполняется вне ее
+fn tagged_in_transaction() -> Response {
+    Response::err(
+        ErrorCode::InvalidState,
+        "requests with id are not allowed inside a transaction",
+    )
+}
+
+fn transaction_too_large() -> Response {
+    Response::err(ErrorCode::TooLarge, "transaction exceeds max_batch_records")
+}
+
+/// Состояние соединения между командами.
+#[derive(Default)]
+struct Session {
+    // шаблоны SUB и позиции чтения по каждому конкретному топику и уровню
+    subs: Vec<String>,
+    cursors: HashMap<(String, u8), u64>,
+    // число POLL-ов: каждый следующий начинает обход со следующего топика
+    polls: usize,
+    // записи открытой транзакции (между BEGIN и COMMIT/ABORT)
+    tx: Option<Vec<TxRecord>>,
+    // выбранная командой BACKPRESSURE, иначе - из limits
+    backpressure: Option<Backpressure>,
+    // уже была хотя бы одна команда: HELLO после этого не принимается
+    started: bool,
+    node_id: Arc<str>,
+}
+
+/// То, что нужно команде без сессии; копия уходит в задачу запроса с id.
+#[derive(Clone)]
+struct Ctx {
+    tx: QueueTx,
+    stats: Arc<Stats>,
+    inboxes: Arc<Inboxes>,
+    // ограничения из конфига, обновляются перед каждой командой
+    limits: Limits,
+    // согласованные в HELLO; None - клиент без рукопожатия
+    features: Option<Vec<Feature>>,
+}
+
+impl Ctx {
+    // без HELLO - как до появления рукопожатия: ни одной возможности
+    fn has(&self, feature: Feature) -> bool {
+        self.features.as_ref().is_some_and(|f| f.contains(&feature))
+    }
+}
+
+/// Запросы с id, которые выполняются, пока соединение читает следующие команды.
+struct InFlight {
+    tasks: JoinSet<bool>,
+    // готовые ответы; в сокет каждый уходит целиком
+    out: mpsc::Sender<Vec<u8>>,
+}
+
+impl InFlight {
+    fn spawn(&mut self, ctx: Ctx, id: u64, cmd: Command, batch: Vec<String>) {
+        let out = self.out.clone();
+        self.tasks.spawn(async move {
+            let mut buf = Vec::new();
+            let keep_open = dispatch(&ctx, &mut buf, cmd, batch).await;
+            tag_reply(&mut buf, id);
+            let _ = out.send(buf).await;
+            keep_open
+        });
+    }
+}
+
+// `#<id> ` перед итоговой строкой ответа; строки данных (FETCH, RECEIVE) идут перед ней как есть
+fn tag_reply(buf: &mut Vec<u8>, id: u64) {
+    if buf.is_empty() {
+        return;
+    }
+    let body = &buf[..buf.len() - 1];
+    let start = body.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    buf.splice(start..start, format!("#{} ", id).into_bytes());
+}
+
+// один писатель на соединение: ответы запросов с id не перемешиваются построчно
+async fn write_out(mut writer: OwnedWriteHalf, mut out: mpsc::Receiver<Vec<u8>>) {
+    while let Some(buf) = out.recv().await {
+        if writer.write_all(&buf).await.is_err() {
+            break;
+        }
+    }
+}
 
 async fn next_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Option<String> {
     match lines.next_line().await {
@@ -24,70 +155,416 @@ async fn next_line(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Option<String
 
 async fn read_line(
     lines: &mut Lines<BufReader<OwnedReadHalf>>,
-    writer: &mut OwnedWriteHalf,
+    writer: &mut Vec<u8>,
     stats: &Stats,
+    timeout_ms: u64,
 ) -> Option<String> {
-    let timed = tokio::time::timeout(READ_TIMEOUT, next_line(lines)).await;
+    let timeout = std::time::Duration::from_millis(timeout_ms);
+    let timed = tokio::time::timeout(timeout, next_line(lines)).await;
 
     match timed {
         Ok(Some(v)) => Some(v),
         Ok(None) => None, // EOF
         Err(_) => {
-            reply(writer, stats, Response::ErrTimeout).await;
+            reply(
+                writer,
+                stats,
+                Response::err(ErrorCode::Timeout, "idle connection closed"),
+            )
+            .await;
             None
         }
     }
 }
 
 async fn process_line(
-    tx: &Sender<Request>,
-    writer: &mut OwnedWriteHalf,
-    stats: &Stats,
+    ctx: &mut Ctx,
+    session: &mut Session,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut Vec<u8>,
+    in_flight: &mut InFlight,
     line: String,
 ) -> bool {
-    if line.len() > MAX_MSG_BYTES {
-        reply(writer, stats, Response::ErrTooLarge).await;
+    let first = !std::mem::replace(&mut session.started, true);
+    if line.len() > ctx.limits.max_msg_bytes {
+        let r = Response::err(ErrorCode::TooLarge, "line exceeds max_msg_bytes");
+        reply(writer, &ctx.stats, r).await;
         return true;
     }
 
-    match Command::parse(&line) {
+    // без согласованных ids строка с `#` - просто неизвестная команда
+    let tagged = split_id(&line).filter(|_| ctx.has(Feature::Ids));
+    let Some((id, rest)) = tagged else {
+        let cmd = Command::parse(&line);
+        return process_command(ctx, session, lines, writer, cmd, first).await;
+    };
+
+    // ошибки до запуска задачи тоже отвечаем с id
+    let mut out = Vec::new();
+    let cmd = Command::parse(rest);
+    let keep_open = match &cmd {
+        Command::Pub { .. } if session.tx.is_some() => {
+            reply(&mut out, &ctx.stats, tagged_in_transaction()).await;
+            true
+        }
+        Command::MPub { count, .. } => {
+            match read_batch(lines, &mut out, &ctx.stats, *count, &ctx.limits).await {
+                Ok(_) if session.tx.is_some() => {
+                    reply(&mut out, &ctx.stats, tagged_in_transaction()).await;
+                    true
+                }
+                Ok(batch) => {
+                    in_flight.spawn(ctx.clone(), id, cmd, batch);
+                    true
+                }
+                Err(keep_open) => keep_open,
+            }
+        }
+        _ => {
+            in_flight.spawn(ctx.clone(), id, cmd, Vec::new());
+            true
+        }
+    };
+    tag_reply(&mut out, id);
+    writer.append(&mut out);
+    keep_open
+}
+
+// команды, которым нужна сессия; остальные - в dispatch
+async fn process_command(
+    ctx: &mut Ctx,
+    session: &mut Session,
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut Vec<u8>,
+    cmd: Command,
+    first: bool,
+) -> bool {
+    let stats = &*ctx.stats;
+    let max_records = ctx.limits.max_batch_records;
+
+    match cmd {
+        Command::Pub {
+            topic,
+            payload,
+            opts,
+        } => match session.tx.as_mut() {
+            Some(staged) => {
+                stage_produce(stats, writer, staged, max_records, topic, payload, opts).await
+            }
+            None => {
+                let cmd = Command::Pub {
+                    topic,
+                    payload,
+                    opts,
+                };
+                dispatch(ctx, writer, cmd, Vec::new()).await
+            }
+        },
+        Command::MPub { topic, count, prio } => {
+            let batch = match read_batch(lines, writer, stats, count, &ctx.limits).await {
+                Ok(batch) => batch,
+                Err(keep_open) => return keep_open,
+            };
+            match session.tx.as_mut() {
+                Some(staged) => {
+                    stage_batch(stats, writer, staged, max_records, topic, prio, batch).await
+                }
+                None => dispatch(ctx, writer, Command::MPub { topic, count, prio }, batch).await,
+            }
+        }
+        Command::Backpressure(policy) => {
+            session.backpressure = Some(policy);
+            reply(writer, stats, Response::Ok).await;
+            true
+        }
+        Command::Hello {
+            version,
+            client,
+            features,
+        } => {
+            if !first {
+                let r = Response::err(ErrorCode::InvalidState, "HELLO must be the first command");
+                reply(writer, stats, r).await;
+                return true;
+            }
+            let version = version.min(PROTOCOL_VERSION);
+            tracing::info!(client = %client, version, "client hello");
+            let r = Response::Hello(Hello {
+                version,
+                broker: env!("CARGO_PKG_VERSION").to_string(),
+                node_id: session.node_id.to_string(),
+                features: features.clone(),
+            });
+            reply(writer, stats, r).await;
+            ctx.features = Some(features);
+            true
+        }
+        Command::Begin => {
+            let r = if session.tx.is_some() {
+                Response::err(ErrorCode::InvalidState, "transaction already open")
+            } else {
+                session.tx = Some(Vec::new());
+                Response::Ok
+            };
+            reply(writer, stats, r).await;
+            true
+        }
+        Command::Commit => match session.tx.take() {
+            Some(staged) => handle_commit(&ctx.tx, stats, writer, staged).await,
+            None => {
+                reply(writer, stats, no_transaction()).await;
+                true
+            }
+        },
+        Command::Abort => {
+            let r = match session.tx.take() {
+                Some(_) => Response::Ok,
+                None => no_transaction(),
+            };
+            reply(writer, stats, r).await;
+            true
+        }
+        Command::Sub { pattern } => {
+            if !session.subs.contains(&pattern) {
+                session.subs.push(pattern);
+            }
+            reply(writer, stats, Response::Ok).await;
+            true
+        }
+        Command::Unsub { pattern } => {
+            session.subs.retain(|p| *p != pattern);
+            reply(writer, stats, Response::Ok).await;
+            true
+        }
+        Command::Poll { limit } => {
+            let limit = limit.min(ctx.limits.max_batch_records);
+            handle_poll(&ctx.tx, stats, writer, session, limit).await
+        }
+        cmd => dispatch(ctx, writer, cmd, Vec::new()).await,
+    }
+}
+
+// команды без сессии: выполняются сразу или в задаче запроса с id;
+// `batch` - уже вычитанные строки MPUB
+async fn dispatch(ctx: &Ctx, writer: &mut Vec<u8>, cmd: Command, batch: Vec<String>) -> bool {
+    let Ctx {
+        tx, stats, inboxes, ..
+    } = ctx;
+    let limits = ctx.limits;
+
+    match cmd {
         Command::Ping => {
             reply(writer, stats, Response::Ok).await;
             true
         }
-        Command::Pub { topic, payload } => handle_produce(tx, stats, writer, topic, payload).await,
+        Command::Pub {
+            topic,
+            payload,
+            opts,
+        } => {
+            let acks = ctx.has(Feature::Acks);
+            handle_produce(tx, stats, writer, topic, payload, opts, acks).await
+        }
+        Command::MPub { topic, prio, .. } => {
+            handle_produce_batch(tx, stats, writer, topic, prio, batch).await
+        }
+        Command::CommitOffset {
+            group,
+            topic,
+            offset,
+            prio,
+            generation,
+        } => {
+            handle_commit_offset(tx, stats, writer, |reply| Request::CommitOffset {
+                group,
+                topic,
+                prio,
+                offset,
+                generation,
+                reply,
+            })
+            .await
+        }
+        Command::Join {
+            group,
+            member,
+            topics,
+            session_timeout_ms,
+            strategy,
+        } => {
+            let session_timeout_ms =
+                session_timeout_ms.unwrap_or(limits.default_session_timeout_ms);
+            handle_membership(
+                tx,
+                stats,
+                writer,
+                |reply| Request::Join {
+                    group,
+                    member,
+                    topics,
+                    session_timeout_ms,
+                    strategy,
+                    reply,
+                },
+                Response::err(
+                    ErrorCode::Conflict,
+                    "group uses a different assignment strategy",
+                ),
+            )
+            .await
+        }
+        Command::Heartbeat { group, member } => {
+            handle_membership(
+                tx,
+                stats,
+                writer,
+                |reply| Request::Heartbeat {
+                    group,
+                    member,
+                    reply,
+                },
+                unknown_member(),
+            )
+            .await
+        }
+        Command::Leave { group, member } => handle_leave(tx, stats, writer, group, member).await,
+        Command::Lag { group } => handle_lag(tx, stats, writer, group).await,
+        Command::Topics => handle_topics(tx, stats, writer).await,
+        Command::ConfigureGroup { group, reset } => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let req = Request::ConfigureGroup {
+                group,
+                reset,
+                reply: reply_tx,
+            };
+            if tx.send(req).await.is_err() || reply_rx.await.is_err() {
+                reply(writer, stats, unavailable()).await;
+                return false;
+            }
+            reply(writer, stats, Response::Ok).await;
+            true
+        }
+        Command::Seek {
+            group,
+            topic,
+            target,
+            prio,
+        } => handle_seek(tx, stats, writer, group, topic, prio, target).await,
         Command::Fetch {
             topic,
             offset,
             limit,
-        } => handle_fetch(tx, stats, writer, topic, offset, limit).await,
+            mut opts,
+        } => {
+            let cap = limits.max_fetch_bytes;
+            opts.max_bytes = Some(opts.max_bytes.map_or(cap, |n| n.min(cap)));
+            // больше записей, чем в пачке MPUB, за раз не отдаем
+            let limit = limit.min(limits.max_batch_records);
+            opts.headers |= ctx.has(Feature::Headers);
+            handle_fetch(tx, stats, writer, topic, offset, limit, opts).await
+        }
+        Command::Create { topic, opts } => handle_create(tx, stats, writer, topic, opts).await,
+        Command::Receive {
+            topic,
+            visibility_ms,
+            headers,
+        } => {
+            let visibility_ms = visibility_ms.unwrap_or(limits.default_visibility_ms);
+            let headers = headers || ctx.has(Feature::Headers);
+            handle_receive(tx, stats, writer, topic, visibility_ms, headers).await
+        }
+        Command::Settle {
+            topic,
+            msg,
+            ack,
+            reason,
+        } => handle_settle(tx, stats, writer, topic, msg, ack, reason).await,
+        Command::Replay {
+            topic,
+            offset,
+            limit,
+        } => {
+            let limit = limit.min(limits.max_batch_records);
+            handle_replay(tx, stats, writer, topic, offset, limit).await
+        }
+        Command::Request {
+            topic,
+            timeout_ms,
+            payload,
+        } => {
+            let timeout_ms = timeout_ms.min(limits.max_request_timeout_ms);
+            handle_request(tx, stats, inboxes, writer, topic, timeout_ms, payload).await
+        }
+        Command::Reply { corr_id, payload } => {
+            if inboxes.deliver(&corr_id, payload) {
+                reply(writer, stats, Response::Ok).await;
+            } else {
+                // запрос уже отвалился по таймауту или id чужой
+                let r = Response::err(ErrorCode::NotFound, "no pending request with this id");
+                reply(writer, stats, r).await;
+            }
+            true
+        }
+        Command::Invalid { line, reason } => {
+            tracing::warn!(cmd = %line, reason, "bad arguments");
+            reply(writer, stats, Response::err(ErrorCode::BadRequest, reason)).await;
+            true
+        }
         Command::Unknown(text) => {
             tracing::warn!(cmd = %text, "unknown command");
-            reply(writer, stats, Response::Nack).await;
+            reply(
+                writer,
+                stats,
+                Response::err(ErrorCode::UnknownCommand, "unknown command"),
+            )
+            .await;
+            true
+        }
+        // без id их разбирает process_command, сюда они доходят только с id
+        Command::Hello { .. }
+        | Command::Backpressure(_)
+        | Command::Begin
+        | Command::Commit
+        | Command::Abort
+        | Command::Sub { .. }
+        | Command::Unsub { .. }
+        | Command::Poll { .. } => {
+            let r = Response::err(
+                ErrorCode::InvalidState,
+                "command changes the session and cannot carry a request id",
+            );
+            reply(writer, stats, r).await;
             true
         }
     }
 }
 
+/// `accepted` - как его отдал `accept`; `node_id` уходит клиенту в ответе на HELLO.
 pub fn spawn_client(
-    socket: TcpStream,
-    peer: SocketAddr,
+    accepted: (TcpStream, SocketAddr),
     tx: Sender<Request>,
     shutdown: Shutdown,
     stats: Arc<Stats>,
+    inboxes: Arc<Inboxes>,
+    settings: Settings,
+    node_id: Arc<str>,
 ) -> tokio::task::JoinHandle<()> {
+    let (socket, peer) = accepted;
     stats.inc_connections();
     tracing::info!(peer = %peer, "client connected");
-    tokio::spawn(handle_client(socket, tx, shutdown, stats))
+    tokio::spawn(handle_client(
+        socket, tx, shutdown, stats, inboxes, settings, node_id,
+    ))
 }
 
 async fn handle_fetch(
     tx: &Sender<Request>,
     stats: &Stats,
-    writer: &mut OwnedWriteHalf,
+    writer: &mut Vec<u8>,
     topic: String,
-    from: u64,
+    from: Option<u64>,
     limit: usize,
+    opts: FetchOpts,
 ) -> bool {
     let (reply_tx, reply_rx) = oneshot::channel();
 
@@ -95,99 +572,855 @@ async fn handle_fetch(
         topic,
         from,
         limit,
+        max_bytes: opts.max_bytes.unwrap_or(usize::MAX),
+        prio: opts.prio,
+        group: opts.group,
+        reset: opts.reset,
         reply: reply_tx,
     };
 
     // отправили запрос в backend
     if tx.send(req).await.is_err() {
-        reply(writer, stats, Response::ErrWal).await;
+        reply(writer, stats, unavailable()).await;
         return false;
     }
 
     // получили ответ от worker
+    let fetched = match reply_rx.await {
+        Ok(Ok(v)) => v,
+        Ok(Err(FetchError::UnknownTopic)) => {
+            reply(writer, stats, unknown_topic()).await;
+            return true;
+        }
+        Ok(Err(FetchError::MemoryTopic)) => {
+            let r = memory_topic("memory topics have no priority levels");
+            reply(writer, stats, r).await;
+            return true;
+        }
+        Ok(Err(FetchError::OffsetOutOfRange)) => {
+            let r = Response::err(
+                ErrorCode::OffsetOutOfRange,
+                "offset is outside the log and reset policy is error",
+            );
+            reply(writer, stats, r).await;
+            return true;
+        }
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+
+    let max_bytes = opts.max_bytes.unwrap_or(usize::MAX);
+
+    // ответ собираем целиком: его размер ограничен max_bytes
+    let mut out = Vec::new();
+    // пустой ответ: следующая страница начинается не раньше начала лога
+    let mut next = fetched.from.max(fetched.start_offset);
+    for e in fetched.records {
+        let line = if opts.headers {
+            // <offset>\t<id>\t<key=base64(value),...|->\t<payload>
+            format!(
+                "{}\t{}\t{}\t{}\n",
+                e.offset,
+                e.id,
+                format_headers(&e.headers),
+                e.payload
+            )
+        } else {
+            format!("{}\t{}\t{}\n", e.offset, e.id, e.payload)
+        };
+        // первая запись отдается всегда, иначе крупная запись остановит чтение топика
+        if !out.is_empty() && out.len() + line.len() > max_bytes {
+            break;
+        }
+        out.extend_from_slice(line.as_bytes());
+        next = e.offset + 1;
+    }
+    let _ = writer.write_all(&out).await;
+
+    let end = Response::FetchEnd {
+        next,
+        high_watermark: fetched.next_offset,
+        log_start: fetched.start_offset,
+    };
+    reply(writer, stats, end).await;
+    true
+}
+
+async fn handle_poll(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    session: &mut Session,
+    limit: usize,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Poll {
+        patterns: session.subs.clone(),
+        cursors: session.cursors.clone(),
+        start: session.polls,
+        limit,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
     let entries = match reply_rx.await {
         Ok(v) => v,
         Err(_) => {
-            reply(writer, stats, Response::ErrWal).await;
+            reply(writer, stats, unavailable()).await;
             return false;
         }
     };
 
-    for e in entries {
-        let line = format!("{}\t{}\t{}\n", e.offset, e.id, e.payload);
+    session.polls = session.polls.wrapping_add(1);
+
+    // <topic>\t<msg-ref>\t<id>\t<payload>; msg-ref как в RECEIVE
+    for (topic, prio, e) in entries {
+        let msg = MsgRef {
+            prio,
+            offset: e.offset,
+        };
+        let line = format!("{}\t{}\t{}\t{}\n", topic, msg, e.id, e.payload);
         let _ = writer.write_all(line.as_bytes()).await;
+        session.cursors.insert((topic, prio), e.offset + 1);
     }
 
     reply(writer, stats, Response::Ok).await;
     true
 }
 
-async fn handle_produce(
+async fn handle_replay(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    topic: String,
+    from: u64,
+    limit: usize,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Replay {
+        topic,
+        from,
+        limit,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let replayed = match reply_rx.await {
+        Ok(v) => v,
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+
+    // <dlq-offset>\t<topic>\t<new-offset>
+    for r in replayed {
+        let line = format!("{}\t{}\t{}\n", r.dlq_offset, r.topic, r.offset);
+        let _ = writer.write_all(line.as_bytes()).await;
+    }
+
+    reply(writer, stats, Response::Ok).await;
+    true
+}
+
+async fn handle_commit_offset(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    make_req: impl FnOnce(oneshot::Sender<CommitResult>) -> Request,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    if tx.send(make_req(reply_tx)).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let r = match reply_rx.await {
+        Ok(CommitResult::Committed) => Response::Ok,
+        Ok(CommitResult::OutOfRange) => Response::err(
+            ErrorCode::OffsetOutOfRange,
+            "offset is past the high watermark",
+        ),
+        Ok(CommitResult::UnknownTopic) => unknown_topic(),
+        Ok(CommitResult::StaleGeneration) => Response::err(
+            ErrorCode::StaleGeneration,
+            "group generation changed, join again",
+        ),
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+    reply(writer, stats, r).await;
+    true
+}
+
+// JOIN и HEARTBEAT отвечают одинаково: поколение группы и назначенные топики
+async fn handle_membership(
     tx: &Sender<Request>,
     stats: &Stats,
-    writer: &mut OwnedWriteHalf,
+    writer: &mut Vec<u8>,
+    make_req: impl FnOnce(oneshot::Sender<Option<Assignment>>) -> Request,
+    missing: Response,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    if tx.send(make_req(reply_tx)).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let r = match reply_rx.await {
+        Ok(Some(a)) => Response::Assignment {
+            generation: a.generation,
+            topics: a.topics,
+        },
+        Ok(None) => missing,
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+    reply(writer, stats, r).await;
+    true
+}
+
+async fn handle_leave(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    group: String,
+    member: String,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Leave {
+        group,
+        member,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let r = match reply_rx.await {
+        Ok(true) => Response::Ok,
+        Ok(false) => unknown_member(),
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+    reply(writer, stats, r).await;
+    true
+}
+
+async fn handle_seek(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    group: String,
+    topic: String,
+    prio: u8,
+    target: SeekTarget,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Seek {
+        group,
+        topic,
+        prio,
+        target,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let r = match reply_rx.await {
+        Ok(SeekResult::Seeked(offset)) => Response::Offset(offset),
+        Ok(SeekResult::UnknownTopic) => unknown_topic(),
+        Ok(SeekResult::OutOfRange) => {
+            Response::err(ErrorCode::OffsetOutOfRange, "target is outside the log")
+        }
+        Ok(SeekResult::GroupActive) => {
+            Response::err(ErrorCode::GroupActive, "group has active members")
+        }
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+    reply(writer, stats, r).await;
+    true
+}
+
+async fn handle_topics(tx: &Sender<Request>, stats: &Stats, writer: &mut Vec<u8>) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    if tx.send(Request::Topics { reply: reply_tx }).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let Ok(topics) = reply_rx.await else {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    };
+
+    // <topic>\t<disk|memory>
+    let mut out = String::new();
+    for (name, kind) in topics {
+        out.push_str(&format!("{}\t{}\n", name, kind.as_str()));
+    }
+    let _ = writer.write_all(out.as_bytes()).await;
+
+    reply(writer, stats, Response::Ok).await;
+    true
+}
+
+async fn handle_lag(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    group: Option<String>,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Lag {
+        group,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let lags = match reply_rx.await {
+        Ok(v) => v,
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+
+    // <group>\t<topic>\t<prio>\t<committed>\t<hw|->\t<lag|->\t<ms-since-commit>
+    let mut out = String::new();
+    for l in lags {
+        let hw = l.high_watermark.map_or("-".to_string(), |v| v.to_string());
+        let lag = l.lag().map_or("-".to_string(), |v| v.to_string());
+        out.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            l.group, l.topic, l.prio, l.committed, hw, lag, l.since_commit_ms
+        ));
+    }
+    let _ = writer.write_all(out.as_bytes()).await;
+
+    reply(writer, stats, Response::Ok).await;
+    true
+}
+
+async fn handle_receive(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    topic: String,
+    visibility_ms: u64,
+    headers: bool,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Receive {
+        topic,
+        visibility_ms,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    let leased = match reply_rx.await {
+        Ok(Ok(v)) => v,
+        Ok(Err(MemoryTopic)) => {
+            let r = memory_topic("memory topics do not support RECEIVE, use FETCH or POLL");
+            reply(writer, stats, r).await;
+            return true;
+        }
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    };
+
+    // <msg-ref>\t<id>\t<attempt>[\t<headers>]\t<payload>, пустая очередь - просто OK
+    if let Some((msg, e, attempt)) = leased {
+        let line = if headers {
+            format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                msg,
+                e.id,
+                attempt,
+                format_headers(&e.headers),
+                e.payload
+            )
+        } else {
+            format!("{}\t{}\t{}\t{}\n", msg, e.id, attempt, e.payload)
+        };
+        let _ = writer.write_all(line.as_bytes()).await;
+    }
+
+    reply(writer, stats, Response::Ok).await;
+    true
+}
+
+async fn handle_settle(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    topic: String,
+    msg: MsgRef,
+    ack: bool,
+    reason: Option<String>,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::Settle {
+        topic,
+        msg,
+        ack,
+        reason,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    match reply_rx.await {
+        Ok(true) => reply(writer, stats, Response::Ok).await,
+        // аренды нет: уже подтверждена или никогда не выдавалась
+        Ok(false) => {
+            let r = Response::err(ErrorCode::NotFound, "no lease for this message");
+            reply(writer, stats, r).await
+        }
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    }
+    true
+}
+
+async fn handle_create(
+    tx: &Sender<Request>,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    topic: String,
+    opts: TopicOpts,
+) -> bool {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = Request::CreateTopic {
+        topic,
+        opts,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        reply(writer, stats, unavailable()).await;
+        return false;
+    }
+
+    match reply_rx.await {
+        Ok(true) => reply(writer, stats, Response::Ok).await,
+        // топик уже существует с другим типом
+        Ok(false) => {
+            let r = Response::err(ErrorCode::Conflict, "topic exists with a different type");
+            reply(writer, stats, r).await
+        }
+        Err(_) => {
+            reply(writer, stats, unavailable()).await;
+            return false;
+        }
+    }
+    true
+}
+
+async fn handle_produce(
+    tx: &QueueTx,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
     topic: String,
     payload: String,
+    opts: PubOpts,
+    acks: bool,
 ) -> bool {
     let (commit_tx, commit_rx) = oneshot::channel();
+    let not_before = opts.not_before(clock::now_ms());
 
-    match try_enqueue(tx, stats, topic, payload, commit_tx) {
+    let opts = ProduceOpts {
+        not_before,
+        ttl_ms: opts.ttl_ms,
+        prio: opts.prio,
+        headers: Vec::new(),
+    };
+
+    match enqueue(tx, stats, topic, payload, opts, commit_tx).await {
         EnqueueResult::Enqueued(id) => {
-            if commit_rx.await.is_ok() {
+            if let Ok(committed) = commit_rx.await {
+                // у отложенной записи offset-а еще нет - ей голый ACK и с acks
+                let r = match committed {
+                    Ok(Some(offset)) if acks => Response::AckRange(offset, offset),
+                    Ok(_) => Response::Ack,
+                    Err(MemoryTopic) => memory_topic("memory topics have no priority levels"),
+                };
                 tracing::info!(id, "committed");
-                reply(writer, stats, Response::Ack).await;
+                reply(writer, stats, r).await;
                 true
             } else {
                 tracing::error!(id, "commit failed");
-                reply(writer, stats, Response::ErrWal).await;
+                reply(writer, stats, wal_failed()).await;
                 false
             }
         }
         EnqueueResult::Full => {
             tracing::error!("queue is full");
-            reply(writer, stats, Response::Nack).await;
+            reply(writer, stats, queue_full()).await;
             true
         }
         EnqueueResult::Closed => false,
     }
 }
 
+async fn handle_request(
+    tx: &QueueTx,
+    stats: &Stats,
+    inboxes: &Inboxes,
+    writer: &mut Vec<u8>,
+    topic: String,
+    timeout_ms: u64,
+    payload: String,
+) -> bool {
+    let (corr_id, reply_rx) = inboxes.open();
+    let (commit_tx, commit_rx) = oneshot::channel();
+
+    let opts = ProduceOpts {
+        not_before: None,
+        ttl_ms: None,
+        prio: 0,
+        headers: vec![(CORRELATION_ID_HEADER.to_string(), corr_id.clone())],
+    };
+
+    match enqueue(tx, stats, topic, payload, opts, commit_tx).await {
+        EnqueueResult::Enqueued(_) => {
+            if commit_rx.await.is_err() {
+                inboxes.close(&corr_id);
+                reply(writer, stats, wal_failed()).await;
+                return false;
+            }
+        }
+        EnqueueResult::Full => {
+            inboxes.close(&corr_id);
+            reply(writer, stats, queue_full()).await;
+            return true;
+        }
+        EnqueueResult::Closed => {
+            inboxes.close(&corr_id);
+            return false;
+        }
+    }
+
+    let timeout = std::time::Duration::from_millis(timeout_ms);
+    match tokio::time::timeout(timeout, reply_rx).await {
+        Ok(Ok(answer)) => {
+            // <correlation-id>\t<payload>
+            let line = format!("{}\t{}\n", corr_id, answer);
+            let _ = writer.write_all(line.as_bytes()).await;
+            reply(writer, stats, Response::Ok).await;
+        }
+        _ => {
+            inboxes.close(&corr_id);
+            tracing::debug!(corr_id = %corr_id, "request timed out");
+            let r = Response::err(ErrorCode::Timeout, "no reply within timeout");
+            reply(writer, stats, r).await;
+        }
+    }
+    true
+}
+
+/// Вычитывает тело MPUB. При ошибке ответ уже отправлен, в Err - оставлять ли
+/// соединение открытым.
+async fn read_batch(
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut Vec<u8>,
+    stats: &Stats,
+    count: usize,
+    limits: &Limits,
+) -> Result<Vec<String>, bool> {
+    if count > limits.max_batch_records {
+        // тело пачки не читаем - клиент рассинхронизирован, закрываем соединение
+        let r = Response::err(ErrorCode::TooLarge, "batch exceeds max_batch_records");
+        reply(writer, stats, r).await;
+        return Err(false);
+    }
+
+    // сначала вычитываем всю пачку, чтобы не рассинхронизировать поток
+    let mut msgs = Vec::with_capacity(count);
+    let mut too_large = false;
+    for _ in 0..count {
+        let Some(line) = read_line(lines, writer, stats, limits.read_timeout_ms).await else {
+            return Err(false);
+        };
+        too_large |= line.len() > limits.max_msg_bytes;
+        msgs.push(line);
+    }
+
+    if too_large {
+        let r = Response::err(ErrorCode::TooLarge, "message exceeds max_msg_bytes");
+        reply(writer, stats, r).await;
+        return Err(true);
+    }
+    Ok(msgs)
+}
+
+async fn handle_produce_batch(
+    tx: &QueueTx,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    topic: String,
+    prio: u8,
+    msgs: Vec<String>,
+) -> bool {
+    let (commit_tx, commit_rx) = oneshot::channel();
+
+    match enqueue_batch(tx, stats, topic, prio, msgs, commit_tx).await {
+        EnqueueResult::Enqueued(id) => {
+            if let Ok(committed) = commit_rx.await {
+                let r = match committed {
+                    Ok((first, last)) => {
+                        tracing::info!(id, first, last, "batch committed");
+                        Response::AckRange(first, last)
+                    }
+                    Err(MemoryTopic) => memory_topic("memory topics have no priority levels"),
+                };
+                reply(writer, stats, r).await;
+                true
+            } else {
+                tracing::error!(id, "batch commit failed");
+                reply(writer, stats, wal_failed()).await;
+                false
+            }
+        }
+        EnqueueResult::Full => {
+            tracing::error!("queue is full");
+            reply(writer, stats, queue_full()).await;
+            true
+        }
+        EnqueueResult::Closed => false,
+    }
+}
+
+async fn stage_produce(
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    staged: &mut Vec<TxRecord>,
+    max_records: usize,
+    topic: String,
+    payload: String,
+    opts: PubOpts,
+) -> bool {
+    // отложенная доставка идет через планировщик и в транзакцию не входит;
+    // TTL в транзакции - только топиковый: на один WAL приходится одна пачка
+    if opts.at_ms.is_some() || opts.delay_ms.is_some() || opts.ttl_ms.is_some() {
+        let r = Response::err(
+            ErrorCode::BadRequest,
+            "AT, DELAY and TTL are not allowed in a transaction",
+        );
+        reply(writer, stats, r).await;
+        return true;
+    }
+    if staged.len() >= max_records {
+        reply(writer, stats, transaction_too_large()).await;
+        return true;
+    }
+
+    staged.push(TxRecord {
+        topic,
+        id: stats.new_id(),
+        msg: payload,
+        prio: opts.prio,
+    });
+    reply(writer, stats, Response::Ok).await;
+    true
+}
+
+async fn stage_batch(
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    staged: &mut Vec<TxRecord>,
+    max_records: usize,
+    topic: String,
+    prio: u8,
+    msgs: Vec<String>,
+) -> bool {
+    if staged.len() + msgs.len() > max_records {
+        reply(writer, stats, transaction_too_large()).await;
+        return true;
+    }
+
+    staged.extend(msgs.into_iter().map(|msg| TxRecord {
+        topic: topic.clone(),
+        id: stats.new_id(),
+        msg,
+        prio,
+    }));
+    reply(writer, stats, Response::Ok).await;
+    true
+}
+
+async fn handle_commit(
+    tx: &QueueTx,
+    stats: &Stats,
+    writer: &mut Vec<u8>,
+    records: Vec<TxRecord>,
+) -> bool {
+    if records.is_empty() {
+        reply(writer, stats, Response::Ok).await;
+        return true;
+    }
+
+    let (commit_tx, commit_rx) = oneshot::channel();
+    let req = Request::Commit {
+        records,
+        committed: commit_tx,
+    };
+
+    match tx.send_write(stats, req).await {
+        Ok(_) => {
+            if let Ok(txid) = commit_rx.await {
+                tracing::info!(txid, "transaction committed");
+                reply(writer, stats, Response::Ok).await;
+                true
+            } else {
+                tracing::error!("transaction commit failed");
+                reply(writer, stats, wal_failed()).await;
+                false
+            }
+        }
+        Err(TrySendError::Full(_)) => {
+            tracing::error!("queue is full");
+            reply(writer, stats, queue_full()).await;
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
 async fn handle_client(
     socket: TcpStream,
     tx: Sender<Request>,
     shutdown: Shutdown,
     stats: Arc<Stats>,
+    inboxes: Arc<Inboxes>,
+    settings: Settings,
+    node_id: Arc<str>,
 ) {
-    let (reader, mut writer) = socket.into_split();
+    let (reader, writer) = socket.into_split();
     let mut lines = BufReader::new(reader).lines();
     let mut shutdown = shutdown;
+    let mut session = Session {
+        node_id,
+        ..Session::default()
+    };
+    let limits = settings.borrow().limits;
+    let mut ctx = Ctx {
+        tx: QueueTx::new(tx, limits.backpressure),
+        stats,
+        inboxes,
+        limits,
+        features: None,
+    };
+
+    let (out, out_rx) = mpsc::channel(limits.max_in_flight);
+    let writer_task = tokio::spawn(write_out(writer, out_rx));
+    let mut in_flight = InFlight {
+        tasks: JoinSet::new(),
+        out,
+    };
 
     loop {
-        tokio::select! {
-            _ = shutdown.changed() => break,
+        ctx.limits = settings.borrow().limits;
+        ctx.tx.backpressure = session.backpressure.unwrap_or(ctx.limits.backpressure);
+        // пока запросы с id в работе, клиент ждет ответов, а не простаивает
+        let read_timeout_ms = if in_flight.tasks.is_empty() {
+            ctx.limits.read_timeout_ms
+        } else {
+            u64::MAX
+        };
+        let can_read = in_flight.tasks.len() < ctx.limits.max_in_flight;
+        let mut buf = Vec::new();
 
-            line = read_line(&mut lines, &mut writer, &stats) => {
-                let Some(line) = line else { break; };
-                if !process_line(&tx, &mut writer, &stats, line).await {
-                    break;
-                }
-            }
+        let keep_open = tokio::select! {
+            _ = shutdown.changed() => false,
+
+            Some(done) = in_flight.tasks.join_next() => done.unwrap_or(false),
+
+            line = read_line(&mut lines, &mut buf, &ctx.stats, read_timeout_ms), if can_read => match line {
+                Some(line) => process_line(&mut ctx, &mut session, &mut lines, &mut buf, &mut in_flight, line).await,
+                None => false,
+            },
+        };
+        if !buf.is_empty() {
+            let _ = in_flight.out.send(buf).await;
+        }
+        if !keep_open {
+            break;
         }
     }
 
+    // начатые запросы с id дорабатывают и отвечают, как и обычный запрос при остановке
+    while in_flight.tasks.join_next().await.is_some() {}
+    drop(in_flight);
+    let _ = writer_task.await;
+
     tracing::info!("client disconnected");
 }
 
 fn record(stats: &Stats, r: &Response) {
     match r {
-        Response::Ack => stats.inc_ack(),
-        Response::Nack => stats.inc_nack(),
-        Response::ErrWal => stats.inc_err_wal(),
+        Response::Ack | Response::AckRange(..) => stats.inc_ack(),
+        Response::Err {
+            code: ErrorCode::Wal,
+            ..
+        } => stats.inc_err_wal(),
+        // остальные ошибки - отказ в запросе
+        Response::Err { .. } => stats.inc_nack(),
         _ => {}
     }
 }
 
-pub(crate) async fn reply(writer: &mut OwnedWriteHalf, stats: &Stats, r: Response) {
+pub(crate) async fn reply(writer: &mut (impl AsyncWrite + Unpin), stats: &Stats, r: Response) {
     record(stats, &r);
-    let _ = writer.write_all(r.as_bytes()).await;
+    let _ = writer.write_all(&r.as_bytes()).await;
 }
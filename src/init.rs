@@ -1,16 +1,19 @@
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::watch;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt};
 
-use crate::config::AppConfig;
+use crate::config::{AppConfig, RuntimeConfig, ServeArgs};
 
 pub type Shutdown = watch::Receiver<bool>;
+// текущие изменяемые на лету настройки, обновляются по SIGHUP
+pub type Settings = watch::Receiver<RuntimeConfig>;
 
-pub fn all() -> Result<(AppConfig, tokio::sync::watch::Receiver<bool>), Box<dyn std::error::Error>>
-{
+pub fn all(args: ServeArgs) -> Result<(AppConfig, Shutdown, Settings), Box<dyn std::error::Error>> {
     init_tracing()?;
 
-    let conf = AppConfig::load();
+    let conf = AppConfig::load(&args)?;
+    conf.check_data_dir()?;
     debug!(
         node_id = %conf.node_id,
         bind_addr = %conf.bind_addr,
@@ -20,9 +23,10 @@ pub fn all() -> Result<(AppConfig, tokio::sync::watch::Receiver<bool>), Box<dyn
 
     debug!(pid = std::process::id(), "process info");
 
-    let shutdown = init_shutdown();
+    let shutdown = init_shutdown()?;
+    let settings = init_reload(args, conf.clone())?;
 
-    Ok((conf, shutdown))
+    Ok((conf, shutdown, settings))
 }
 
 pub fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
@@ -40,7 +44,47 @@ pub fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-pub fn init_shutdown() -> watch::Receiver<bool> {
-    let (_tx, rx) = watch::channel(false);
-    rx
+/// Канал остановки: переходит в true по SIGTERM (systemd, Kubernetes) или SIGINT (Ctrl+C).
+pub fn init_shutdown() -> std::io::Result<Shutdown> {
+    let (tx, rx) = watch::channel(false);
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!(signal = "SIGTERM", "shutdown requested"),
+            _ = sigint.recv() => info!(signal = "SIGINT", "shutdown requested"),
+        }
+        let _ = tx.send(true);
+    });
+
+    Ok(rx)
+}
+
+/// По SIGHUP конфиг перечитывается теми же слоями, что и при старте.
+/// Битый конфиг не применяется: остаются прежние настройки.
+pub fn init_reload(args: ServeArgs, conf: AppConfig) -> std::io::Result<Settings> {
+    let (tx, rx) = watch::channel(conf.runtime.clone());
+
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            let next = match AppConfig::load(&args) {
+                Ok(next) => next,
+                Err(e) => {
+                    error!(error = %e, "config reload failed, keeping current settings");
+                    continue;
+                }
+            };
+            for key in conf.restart_required(&next) {
+                warn!(key, "setting changed, restart required to apply");
+            }
+            tx.send_replace(next.runtime);
+            info!("config reloaded");
+        }
+    });
+
+    Ok(rx)
 }
@@ -0,0 +1,298 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions, rename},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const LEASES_LOG: &str = "leases.log";
+// после стольких ACK, NACK и повторных выдач лог переписывается только с активными арендами
+const COMPACT_AFTER_SETTLED: usize = 1024;
+
+pub struct Lease {
+    pub attempts: u32,
+    pub deadline_ms: u64,
+    // причина последнего NACKMSG в рамках текущей аренды
+    pub reason: Option<String>,
+}
+
+/// Состояние очереди поверх WAL топика: какие записи выданы консьюмерам и до какого момента.
+/// Формат строк `<topic>/leases.log`:
+///   `C\t<cursor>` - все записи ниже cursor уже выдавались
+///   `L\t<offset>\t<attempts>\t<deadline_ms>` - запись выдана в аренду
+///   `A\t<offset>` - обработка подтверждена
+///   `N\t<offset>[\t<reason_b64>]` - консьюмер отказался, запись можно выдать снова
+pub struct Leases {
+    file: File,
+    path: PathBuf,
+    cursor: u64,
+    active: BTreeMap<u64, Lease>,
+    settled_since_compact: usize,
+}
+
+impl Leases {
+    pub fn open(topic_dir: &Path) -> std::io::Result<Self> {
+        let path = topic_dir.join(LEASES_LOG);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let mut l = Leases {
+            file,
+            path,
+            cursor: 0,
+            active: BTreeMap::new(),
+            settled_since_compact: 0,
+        };
+
+        l.recover()?;
+        Ok(l)
+    }
+
+    fn recover(&mut self) -> std::io::Result<()> {
+        let f = OpenOptions::new().read(true).open(&self.path)?;
+        let mut valid_end: u64 = 0;
+
+        for line in BufReader::new(f).lines() {
+            let Ok(line) = line else { break };
+            if !self.replay(&line) {
+                break;
+            }
+            valid_end += (line.len() + 1) as u64;
+        }
+
+        // обрезаем битый хвост
+        self.file.set_len(valid_end)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn replay(&mut self, line: &str) -> bool {
+        let mut it = line.split('\t');
+        let kind = it.next();
+        let Some(offset) = it.next().and_then(|v| v.parse::<u64>().ok()) else {
+            return false;
+        };
+
+        match kind {
+            Some("C") => self.cursor = self.cursor.max(offset),
+            Some("L") => {
+                let attempts = it.next().and_then(|v| v.parse::<u32>().ok());
+                let deadline_ms = it.next().and_then(|v| v.parse::<u64>().ok());
+                let (Some(attempts), Some(deadline_ms)) = (attempts, deadline_ms) else {
+                    return false;
+                };
+                self.cursor = self.cursor.max(offset + 1);
+                let prev = self.active.insert(
+                    offset,
+                    Lease {
+                        attempts,
+                        deadline_ms,
+                        reason: None,
+                    },
+                );
+                if prev.is_some() {
+                    self.settled_since_compact += 1;
+                }
+            }
+            Some("A") => {
+                self.active.remove(&offset);
+                self.settled_since_compact += 1;
+            }
+            Some("N") => {
+                let reason = it
+                    .next()
+                    .and_then(|v| STANDARD.decode(v).ok())
+                    .and_then(|b| String::from_utf8(b).ok());
+                if let Some(l) = self.active.get_mut(&offset) {
+                    l.deadline_ms = 0;
+                    l.reason = reason;
+                }
+                self.settled_since_compact += 1;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Первая еще не выданная запись.
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Запись, аренда которой истекла (или отклонена) и которую пора выдать снова.
+    /// Исчерпавшие `max_attempts` не выдаются - они ждут переноса в dead-letter топик.
+    pub fn next_redelivery(&self, now_ms: u64, max_attempts: Option<u32>) -> Option<u64> {
+        self.active
+            .iter()
+            .find(|(_, l)| {
+                l.deadline_ms <= now_ms && max_attempts.is_none_or(|max| l.attempts < max)
+            })
+            .map(|(off, _)| *off)
+    }
+
+    /// Истекшие аренды, исчерпавшие `max_attempts`: (offset, attempts, reason).
+    pub fn exhausted(&self, now_ms: u64, max_attempts: u32) -> Vec<(u64, u32, Option<String>)> {
+        self.active
+            .iter()
+            .filter(|(_, l)| l.deadline_ms <= now_ms && l.attempts >= max_attempts)
+            .map(|(off, l)| (*off, l.attempts, l.reason.clone()))
+            .collect()
+    }
+
+    pub fn attempts(&self, offset: u64) -> u32 {
+        self.active.get(&offset).map(|l| l.attempts).unwrap_or(0)
+    }
+
+    /// Выдает запись в аренду до `now_ms + visibility_ms`, возвращает номер попытки.
+    pub fn lease(&mut self, offset: u64, now_ms: u64, visibility_ms: u64) -> std::io::Result<u32> {
+        let attempts = self.attempts(offset) + 1;
+        let deadline_ms = now_ms.saturating_add(visibility_ms);
+
+        writeln!(self.file, "L\t{}\t{}\t{}", offset, attempts, deadline_ms)?;
+        self.file.sync_all()?;
+
+        self.cursor = self.cursor.max(offset + 1);
+        let prev = self.active.insert(
+            offset,
+            Lease {
+                attempts,
+                deadline_ms,
+                reason: None,
+            },
+        );
+        // повторная выдача тоже оставляет в логе устаревшую строку
+        if prev.is_some() {
+            self.note_settled()?;
+        }
+        Ok(attempts)
+    }
+
+    /// Подтверждает (`ack`) или отклоняет аренду. `false` - такой аренды нет.
+    pub fn settle(
+        &mut self,
+        offset: u64,
+        ack: bool,
+        reason: Option<&str>,
+    ) -> std::io::Result<bool> {
+        if !self.active.contains_key(&offset) {
+            return Ok(false);
+        }
+
+        match (ack, reason) {
+            (true, _) => writeln!(self.file, "A\t{}", offset)?,
+            (false, None) => writeln!(self.file, "N\t{}", offset)?,
+            (false, Some(r)) => writeln!(
+                self.file,
+                "N\t{}\t{}",
+                offset,
+                STANDARD.encode(r.as_bytes())
+            )?,
+        }
+        self.file.sync_all()?;
+
+        if ack {
+            self.active.remove(&offset);
+        } else if let Some(l) = self.active.get_mut(&offset) {
+            l.deadline_ms = 0;
+            l.reason = reason.map(|r| r.to_string());
+        }
+        self.note_settled()?;
+        Ok(true)
+    }
+
+    fn note_settled(&mut self) -> std::io::Result<()> {
+        self.settled_since_compact += 1;
+        if self.settled_since_compact >= COMPACT_AFTER_SETTLED {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("log.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            writeln!(f, "C\t{}", self.cursor)?;
+            for (off, l) in &self.active {
+                writeln!(f, "L\t{}\t{}\t{}", off, l.attempts, l.deadline_ms)?;
+                // причина NACK нужна при переносе в dead-letter топик
+                if let Some(r) = &l.reason {
+                    writeln!(f, "N\t{}\t{}", off, STANDARD.encode(r.as_bytes()))?;
+                }
+            }
+            f.sync_all()?;
+        }
+        rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.path)?;
+        self.settled_since_compact = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leases_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut l = Leases::open(dir.path()).unwrap();
+        assert_eq!(l.lease(0, 1_000, 100).unwrap(), 1);
+        assert_eq!(l.lease(0, 1_200, 100).unwrap(), 2);
+        l.lease(1, 1_200, 100).unwrap();
+
+        let l = Leases::open(dir.path()).unwrap();
+        assert_eq!((l.attempts(0), l.attempts(1)), (2, 1));
+        assert_eq!(l.cursor(), 2);
+        // до дедлайна повторно не выдается, после - первой идет младшая запись
+        assert_eq!(l.next_redelivery(1_299, None), None);
+        assert_eq!(l.next_redelivery(1_300, None), Some(0));
+        assert_eq!(l.next_redelivery(1_300, Some(2)), Some(1));
+    }
+
+    #[test]
+    fn nacks_and_redeliveries_trigger_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut l = Leases::open(dir.path()).unwrap();
+        l.lease(0, 0, 100).unwrap();
+        for i in 0..COMPACT_AFTER_SETTLED as u64 {
+            if i % 2 == 0 {
+                l.settle(0, false, None).unwrap();
+            } else {
+                l.lease(0, i, 100).unwrap();
+            }
+        }
+
+        let log = std::fs::read_to_string(dir.path().join(LEASES_LOG)).unwrap();
+        assert!(log.lines().count() < 4, "{}", log);
+        let l = Leases::open(dir.path()).unwrap();
+        assert_eq!(l.attempts(0), 1 + COMPACT_AFTER_SETTLED as u32 / 2);
+    }
+
+    #[test]
+    fn compaction_keeps_nack_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut l = Leases::open(dir.path()).unwrap();
+        l.lease(0, 1_000, 100).unwrap();
+        l.lease(1, 1_000, 100).unwrap();
+        assert!(l.settle(0, false, Some("bad\tinput")).unwrap());
+        assert!(l.settle(1, true, None).unwrap());
+        l.compact().unwrap();
+
+        let l = Leases::open(dir.path()).unwrap();
+        assert_eq!(
+            l.exhausted(1_000, 1),
+            [(0, 1, Some("bad\tinput".to_string()))]
+        );
+        assert_eq!(l.cursor(), 2);
+    }
+}
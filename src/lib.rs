@@ -0,0 +1,24 @@
+// брокер `samovaroff` библиотекой: сервер, офлайн-утилиты и асинхронный клиент
+mod broker;
+pub mod cli;
+pub mod client;
+mod clock;
+pub mod config;
+mod group;
+mod inbox;
+mod ingress;
+pub mod init;
+mod lease;
+mod membership;
+mod memlog;
+pub mod protocol;
+mod queue;
+mod schedule;
+pub mod service;
+mod stats;
+mod topic;
+mod txn;
+mod wal;
+mod worker;
+
+pub use broker::{Broker, BrokerBuilder, BrokerHandle};
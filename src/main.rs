@@ -1,33 +1,39 @@
-mod config;
-mod ingress;
-mod init;
-mod protocol;
-mod queue;
-mod service;
-mod stats;
-mod wal;
-mod worker;
-
-use service::Service;
-use tokio::sync::watch;
+use std::process::ExitCode;
+
+use clap::Parser;
+
+use samovaroff_broker::cli::{self, Cli, CliCommand};
+use samovaroff_broker::config::ServeArgs;
+use samovaroff_broker::init;
+use samovaroff_broker::service::Service;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // без подкоманды запускается брокер
+    match cli
+        .command
+        .unwrap_or_else(|| CliCommand::Serve(Box::default()))
+    {
+        CliCommand::Serve(args) => match serve(*args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::from(cli::EXIT_FAILURE)
+            }
+        },
+        CliCommand::Wal(cmd) => cli::wal(cmd),
+        CliCommand::Topics(cmd) => cli::topics(cmd),
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (conf, _shutdown) = init::all()?;
-
-    let (shutdown_tx, shutdown_rx) = watch::channel(false);
-
-    // Ждем Ctrl+C и шлем сигнал остановки
-    tokio::spawn(async move {
-        let _ = tokio::signal::ctrl_c().await;
-        tracing::info!("shutdown requested");
-
-        let _ = shutdown_tx.send(true);
-    });
+async fn serve(args: ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (conf, shutdown, settings) = init::all(args)?;
 
-    let service = Service::new(&conf);
+    let service = Service::new(&conf, settings);
 
-    service.start(&shutdown_rx).await?;
+    service.start(&shutdown).await?;
 
     Ok(())
 }
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::protocol::AssignStrategy;
+
+struct Member {
+    topics: Vec<String>,
+    session_timeout_ms: u64,
+    last_seen_ms: u64,
+}
+
+struct Group {
+    generation: u64,
+    strategy: AssignStrategy,
+    members: BTreeMap<String, Member>,
+    assignment: BTreeMap<String, Vec<String>>,
+}
+
+/// Назначение участника в текущем поколении группы.
+pub struct Assignment {
+    pub generation: u64,
+    pub topics: Vec<String>,
+}
+
+/// Живые участники consumer-групп и распределение топиков между ними.
+/// Хранится только в памяти: после рестарта участники заходят в группы заново.
+/// Время передается снаружи (`now_ms`), чтобы его можно было подменять.
+#[derive(Default)]
+pub struct Membership {
+    groups: HashMap<String, Group>,
+    // поколения новых групп начинаются отсюда; у следующего запуска брокера - больше
+    first_generation: u64,
+}
+
+impl Membership {
+    /// `epoch` - номер запуска брокера: поколения прошлых запусков не совпадут с новыми.
+    pub fn new(epoch: u64) -> Self {
+        Membership {
+            groups: HashMap::new(),
+            first_generation: epoch << 32,
+        }
+    }
+
+    /// Добавляет участника или обновляет его подписку. Меняет поколение, если изменился состав.
+    /// None - стратегия не совпадает с выбранной группой.
+    pub fn join(
+        &mut self,
+        group: &str,
+        member: &str,
+        topics: Vec<String>,
+        session_timeout_ms: u64,
+        strategy: AssignStrategy,
+        now_ms: u64,
+    ) -> Option<Assignment> {
+        let first_generation = self.first_generation;
+        let g = self
+            .groups
+            .entry(group.to_string())
+            .or_insert_with(|| Group {
+                generation: first_generation,
+                strategy,
+                members: BTreeMap::new(),
+                assignment: BTreeMap::new(),
+            });
+
+        // стратегию выбирает первый участник
+        if g.members.is_empty() {
+            g.strategy = strategy;
+        } else if g.strategy != strategy {
+            return None;
+        }
+
+        let changed = g.members.get(member).is_none_or(|m| m.topics != topics);
+        g.members.insert(
+            member.to_string(),
+            Member {
+                topics,
+                session_timeout_ms,
+                last_seen_ms: now_ms,
+            },
+        );
+        if changed {
+            g.rebalance();
+        }
+        Some(g.assignment_of(member))
+    }
+
+    /// None - участника нет в группе (вышел по таймауту), нужно снова сделать JOIN.
+    pub fn heartbeat(&mut self, group: &str, member: &str, now_ms: u64) -> Option<Assignment> {
+        let g = self.groups.get_mut(group)?;
+        g.members.get_mut(member)?.last_seen_ms = now_ms;
+        Some(g.assignment_of(member))
+    }
+
+    pub fn leave(&mut self, group: &str, member: &str) -> bool {
+        let Some(g) = self.groups.get_mut(group) else {
+            return false;
+        };
+        if g.members.remove(member).is_none() {
+            return false;
+        }
+        g.rebalance();
+        true
+    }
+
+    /// Убирает участников без heartbeat дольше их session timeout.
+    /// Возвращает (группа, участник) удаленных.
+    pub fn expire(&mut self, now_ms: u64) -> Vec<(String, String)> {
+        let mut removed = Vec::new();
+        for (name, g) in self.groups.iter_mut() {
+            let dead: Vec<String> = g
+                .members
+                .iter()
+                .filter(|(_, m)| m.last_seen_ms.saturating_add(m.session_timeout_ms) < now_ms)
+                .map(|(id, _)| id.clone())
+                .collect();
+            if dead.is_empty() {
+                continue;
+            }
+            for id in dead {
+                g.members.remove(&id);
+                removed.push((name.clone(), id));
+            }
+            g.rebalance();
+        }
+        removed
+    }
+
+    /// Можно ли коммитить offset от имени поколения `generation`.
+    /// Без поколения коммитят только группы без живых участников; с поколением -
+    /// только текущее, в том числе после выхода последнего участника.
+    pub fn check_generation(&self, group: &str, generation: Option<u64>) -> bool {
+        let g = self.groups.get(group);
+        match generation {
+            None => g.is_none_or(|g| g.members.is_empty()),
+            // группы нет в этом запуске - поколение выдано до рестарта
+            Some(generation) => g.is_some_and(|g| g.generation == generation),
+        }
+    }
+}
+
+impl Group {
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        self.assignment.clear();
+
+        let members: Vec<&String> = self.members.keys().collect();
+        let topics: BTreeSet<&String> = self.members.values().flat_map(|m| &m.topics).collect();
+
+        for (i, topic) in topics.iter().enumerate() {
+            let eligible: Vec<&String> = members
+                .iter()
+                .copied()
+                .filter(|id| self.members[*id].topics.contains(topic))
+                .collect();
+
+            let preferred = match self.strategy {
+                // подряд идущие топики достаются одному участнику
+                AssignStrategy::Range => members[i * members.len() / topics.len()],
+                AssignStrategy::RoundRobin => members[i % members.len()],
+            };
+            // участник, не подписанный на топик, его не получает
+            let owner = if eligible.contains(&preferred) {
+                preferred
+            } else {
+                eligible[i % eligible.len()]
+            };
+
+            self.assignment
+                .entry(owner.clone())
+                .or_default()
+                .push((*topic).clone());
+        }
+    }
+
+    fn assignment_of(&self, member: &str) -> Assignment {
+        Assignment {
+            generation: self.generation,
+            topics: self.assignment.get(member).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn join(m: &mut Membership, member: &str, strategy: AssignStrategy, now_ms: u64) -> Assignment {
+        let all = topics(&["t1", "t2", "t3", "t4"]);
+        m.join("g", member, all, 1000, strategy, now_ms).unwrap()
+    }
+
+    #[test]
+    fn session_expires_without_heartbeat() {
+        let mut m = Membership::default();
+        join(&mut m, "a", AssignStrategy::Range, 0);
+        join(&mut m, "b", AssignStrategy::Range, 0);
+
+        // a отмечается, b молчит дольше session timeout
+        assert!(m.heartbeat("g", "a", 900).is_some());
+        assert!(m.expire(1000).is_empty());
+        assert_eq!(m.expire(1001), vec![("g".to_string(), "b".to_string())]);
+
+        assert!(m.heartbeat("g", "b", 1002).is_none());
+        let a = m.heartbeat("g", "a", 1002).unwrap();
+        assert_eq!(a.topics, topics(&["t1", "t2", "t3", "t4"]));
+    }
+
+    #[test]
+    fn generation_changes_on_join_and_leave() {
+        let mut m = Membership::default();
+        assert_eq!(join(&mut m, "a", AssignStrategy::Range, 0).generation, 1);
+        assert_eq!(join(&mut m, "b", AssignStrategy::Range, 0).generation, 2);
+        // повторный JOIN с той же подпиской состав не меняет
+        assert_eq!(join(&mut m, "b", AssignStrategy::Range, 10).generation, 2);
+        assert!(m.leave("g", "b"));
+        assert_eq!(m.heartbeat("g", "a", 20).unwrap().generation, 3);
+        assert!(!m.leave("g", "b"));
+    }
+
+    #[test]
+    fn range_assigns_consecutive_topics() {
+        let mut m = Membership::default();
+        join(&mut m, "a", AssignStrategy::Range, 0);
+        join(&mut m, "b", AssignStrategy::Range, 0);
+        assert_eq!(
+            m.heartbeat("g", "a", 1).unwrap().topics,
+            topics(&["t1", "t2"])
+        );
+        assert_eq!(
+            m.heartbeat("g", "b", 1).unwrap().topics,
+            topics(&["t3", "t4"])
+        );
+    }
+
+    #[test]
+    fn round_robin_alternates_topics() {
+        let mut m = Membership::default();
+        join(&mut m, "a", AssignStrategy::RoundRobin, 0);
+        join(&mut m, "b", AssignStrategy::RoundRobin, 0);
+        assert_eq!(
+            m.heartbeat("g", "a", 1).unwrap().topics,
+            topics(&["t1", "t3"])
+        );
+        assert_eq!(
+            m.heartbeat("g", "b", 1).unwrap().topics,
+            topics(&["t2", "t4"])
+        );
+
+        // стратегию группы выбрал первый участник
+        let other = m.join("g", "c", topics(&["t1"]), 1000, AssignStrategy::Range, 2);
+        assert!(other.is_none());
+    }
+
+    #[test]
+    fn stale_generation_cannot_commit() {
+        let mut m = Membership::default();
+        // группа без участников коммитит и без поколения
+        assert!(m.check_generation("g", None));
+
+        let first = join(&mut m, "a", AssignStrategy::Range, 0).generation;
+        let current = join(&mut m, "b", AssignStrategy::Range, 0).generation;
+        assert!(m.check_generation("g", Some(current)));
+        assert!(!m.check_generation("g", Some(first)));
+        assert!(!m.check_generation("g", None));
+
+        // после выхода всех по таймауту коммит без поколения снова разрешен,
+        // а старое поколение так и остается устаревшим
+        m.expire(5000);
+        assert!(m.check_generation("g", None));
+        assert!(!m.check_generation("g", Some(current)));
+    }
+
+    #[test]
+    fn expired_member_cannot_commit_with_old_generation() {
+        let mut m = Membership::default();
+        let old = join(&mut m, "a", AssignStrategy::Range, 0).generation;
+        assert_eq!(m.expire(1001), vec![("g".to_string(), "a".to_string())]);
+        assert!(!m.check_generation("g", Some(old)));
+
+        // группа ожила: у нового участника другое поколение, старое все еще отвергается
+        let new = join(&mut m, "b", AssignStrategy::Range, 2000).generation;
+        assert!(new > old);
+        assert!(!m.check_generation("g", Some(old)));
+        assert!(m.check_generation("g", Some(new)));
+    }
+
+    #[test]
+    fn generations_grow_across_restarts() {
+        let mut before = Membership::new(1);
+        let old = join(&mut before, "a", AssignStrategy::Range, 0).generation;
+
+        // после рестарта групп нет: старое поколение не принимается и не выдается снова
+        let mut after = Membership::new(2);
+        assert!(!after.check_generation("g", Some(old)));
+        let new = join(&mut after, "a", AssignStrategy::Range, 0).generation;
+        assert!(new > old);
+        assert!(!after.check_generation("g", Some(old)));
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+
+use crate::wal::{WalRecord, record_bytes};
+
+struct MemRecord {
+    offset: u64,
+    id: u64,
+    payload: String,
+    headers: Vec<(String, String)>,
+    ts_ms: u64,
+    expires_at: Option<u64>,
+}
+
+/// Лог эфемерного топика: кольцевой буфер в памяти с теми же offset-ами, что у WAL.
+/// На диск ничего не пишется, после рестарта топик нужно создать заново.
+pub struct MemLog {
+    capacity: usize,
+    pub ttl_ms: Option<u64>,
+    next_offset: u64,
+    records: VecDeque<MemRecord>,
+}
+
+impl MemLog {
+    pub fn new(capacity: usize, ttl_ms: Option<u64>) -> Self {
+        MemLog {
+            capacity: capacity.max(1),
+            ttl_ms,
+            next_offset: 0,
+            records: VecDeque::new(),
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    /// Срок жизни записи: TTL сообщения важнее TTL топика.
+    pub fn expires_at(&self, now_ms: u64, msg_ttl_ms: Option<u64>) -> Option<u64> {
+        msg_ttl_ms
+            .or(self.ttl_ms)
+            .map(|ttl| now_ms.saturating_add(ttl))
+    }
+
+    pub fn append(
+        &mut self,
+        id: u64,
+        msg: &str,
+        ts_ms: u64,
+        expires_at: Option<u64>,
+        headers: &[(&str, &str)],
+    ) -> u64 {
+        let offset = self.next_offset;
+        self.records.push_back(MemRecord {
+            offset,
+            id,
+            payload: msg.to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ts_ms,
+            expires_at,
+        });
+        self.next_offset += 1;
+        self.evict();
+        offset
+    }
+
+    /// Offset-ы первой и последней записи; у пустой пачки их нет.
+    pub fn append_batch(
+        &mut self,
+        records: &[(u64, String)],
+        ts_ms: u64,
+        expires_at: Option<u64>,
+    ) -> Option<(u64, u64)> {
+        let first = self.next_offset;
+        for (id, msg) in records {
+            self.append(*id, msg, ts_ms, expires_at, &[]);
+        }
+        (self.next_offset > first).then(|| (first, self.next_offset - 1))
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Самая старая запись, еще не вытесненная из буфера.
+    pub fn start_offset(&self) -> u64 {
+        self.records.front().map_or(self.next_offset, |r| r.offset)
+    }
+
+    /// Как `Wal::offset_for_time`.
+    pub fn offset_for_time(&self, ts_ms: u64) -> u64 {
+        self.records
+            .iter()
+            .find(|r| r.ts_ms >= ts_ms)
+            .map_or(self.next_offset, |r| r.offset)
+    }
+
+    /// Как `Wal::read_from`: до `limit` записей и около `max_bytes` payload-а
+    /// начиная с `from`, без просроченных.
+    pub fn read_from(
+        &self,
+        from: u64,
+        limit: usize,
+        max_bytes: usize,
+        now_ms: u64,
+    ) -> (Vec<WalRecord>, u64) {
+        let mut out = Vec::new();
+        let mut expired: u64 = 0;
+        let mut bytes: usize = 0;
+
+        let start = self.records.front().map(|r| r.offset).unwrap_or(0);
+        let skip = from.saturating_sub(start) as usize;
+
+        for r in self.records.iter().skip(skip) {
+            if out.len() >= limit || bytes >= max_bytes {
+                break;
+            }
+            if r.expires_at.is_some_and(|exp| exp <= now_ms) {
+                expired += 1;
+                continue;
+            }
+            out.push(WalRecord {
+                offset: r.offset,
+                id: r.id,
+                payload: r.payload.clone(),
+                headers: r.headers.clone(),
+            });
+            bytes += record_bytes(&r.payload, &r.headers);
+        }
+
+        (out, expired)
+    }
+
+    // самые старые записи вытесняются при переполнении
+    fn evict(&mut self) {
+        while self.records.len() > self.capacity {
+            self.records.pop_front();
+        }
+    }
+}
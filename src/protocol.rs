@@ -1,85 +1,1485 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+
+/// Старший уровень приоритета; 0 - уровень по умолчанию.
+pub const MAX_PRIORITY: u8 = 9;
+
+// версия строкового протокола; HELLO договаривается о min(клиент, брокер)
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const BAD_PRIO: &str = "PRIO must be 0-9";
+const BAD_RESET: &str = "RESET must be earliest, latest or error";
+const BAD_PATTERN: &str = "bad topic pattern";
+const BAD_TOPIC: &str =
+    "topic must be 1-255 of [A-Za-z0-9._-], without '..' and not starting with '.' or '__'";
+
+// команды с обязательными аргументами: без них это BAD_REQUEST, а не UNKNOWN_COMMAND
+const VERBS_WITH_ARGS: &[&str] = &[
+    "PUB",
+    "MPUB",
+    "FETCH",
+    "CREATE",
+    "RECEIVE",
+    "ACKMSG",
+    "NACKMSG",
+    "BACKPRESSURE",
+    "HELLO",
+    "SUB",
+    "UNSUB",
+    "POLL",
+    "REQUEST",
+    "REPLY",
+    "REPLAY",
+    "COMMITOFFSET",
+    "JOIN",
+    "HEARTBEAT",
+    "LEAVE",
+    "GROUP",
+    "SEEK",
+];
+
+fn parse_prio(v: &str) -> Option<u8> {
+    v.parse::<u8>().ok().filter(|p| *p <= MAX_PRIORITY)
+}
+
+/// Ссылка на запись очереди из ответа RECEIVE: `<offset>` для уровня 0,
+/// `<prio>:<offset>` для остальных. Клиент возвращает ее как есть в ACKMSG/NACKMSG.
+pub struct MsgRef {
+    pub prio: u8,
+    pub offset: u64,
+}
+
+impl MsgRef {
+    fn parse(v: &str) -> Option<Self> {
+        match v.split_once(':') {
+            Some((prio, offset)) => Some(MsgRef {
+                prio: parse_prio(prio)?,
+                offset: offset.parse::<u64>().ok()?,
+            }),
+            None => Some(MsgRef {
+                prio: 0,
+                offset: v.parse::<u64>().ok()?,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for MsgRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prio == 0 {
+            write!(f, "{}", self.offset)
+        } else {
+            write!(f, "{}:{}", self.prio, self.offset)
+        }
+    }
+}
+
+/// Код ошибки в ответе `ERR <CODE> retriable=<true|false> <detail>`.
+/// Коды стабильны: клиенты сравнивают по ним, текст detail - только для людей.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // строка не разобралась как команда
+    UnknownCommand,
+    // команда разобралась, но параметры недопустимы
+    BadRequest,
+    // команда не к месту: COMMIT без BEGIN и т.п.
+    InvalidState,
+    TooLarge,
+    UnknownTopic,
+    UnknownMember,
+    StaleGeneration,
+    OffsetOutOfRange,
+    // аренды или ожидающего запроса с таким id нет
+    NotFound,
+    // противоречит уже существующему: тип топика, стратегия группы
+    Conflict,
+    GroupActive,
+    // очередь к worker заполнена
+    QueueFull,
+    // лимит соединений
+    Busy,
+    Timeout,
+    // worker остановлен, брокер завершается
+    Unavailable,
+    // запись в WAL не удалась
+    Wal,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 16] = [
+        ErrorCode::UnknownCommand,
+        ErrorCode::BadRequest,
+        ErrorCode::InvalidState,
+        ErrorCode::TooLarge,
+        ErrorCode::UnknownTopic,
+        ErrorCode::UnknownMember,
+        ErrorCode::StaleGeneration,
+        ErrorCode::OffsetOutOfRange,
+        ErrorCode::NotFound,
+        ErrorCode::Conflict,
+        ErrorCode::GroupActive,
+        ErrorCode::QueueFull,
+        ErrorCode::Busy,
+        ErrorCode::Timeout,
+        ErrorCode::Unavailable,
+        ErrorCode::Wal,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::UnknownCommand => "UNKNOWN_COMMAND",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::InvalidState => "INVALID_STATE",
+            ErrorCode::TooLarge => "TOO_LARGE",
+            ErrorCode::UnknownTopic => "UNKNOWN_TOPIC",
+            ErrorCode::UnknownMember => "UNKNOWN_MEMBER",
+            ErrorCode::StaleGeneration => "STALE_GENERATION",
+            ErrorCode::OffsetOutOfRange => "OFFSET_OUT_OF_RANGE",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Conflict => "CONFLICT",
+            ErrorCode::GroupActive => "GROUP_ACTIVE",
+            ErrorCode::QueueFull => "QUEUE_FULL",
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::Unavailable => "UNAVAILABLE",
+            ErrorCode::Wal => "WAL",
+        }
+    }
+
+    pub fn parse(v: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.as_str() == v)
+    }
+
+    /// Тот же запрос можно повторить позже; иначе его надо исправить.
+    pub fn retriable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::GroupActive
+                | ErrorCode::QueueFull
+                | ErrorCode::Busy
+                | ErrorCode::Timeout
+                | ErrorCode::Unavailable
+                | ErrorCode::Wal
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Response {
     Ack,
-    Nack,
+    AckRange(u64, u64),
     Ok,
-    ErrWal,
-    ErrBusy,
-    ErrTimeout,
-    ErrTooLarge,
+    // конец ответа FETCH: offset следующей страницы, high watermark и начало лога
+    FetchEnd {
+        next: u64,
+        high_watermark: u64,
+        log_start: u64,
+    },
+    Err {
+        code: ErrorCode,
+        detail: Cow<'static, str>,
+    },
+    // ответ SEEK: новый offset группы
+    Offset(u64),
+    // ответ JOIN/HEARTBEAT: поколение группы и топики участника
+    Assignment {
+        generation: u64,
+        topics: Vec<String>,
+    },
+    Hello(Hello),
 }
 
 impl Response {
-    pub fn as_bytes(&self) -> &'static [u8] {
+    pub fn as_bytes(&self) -> Cow<'static, [u8]> {
+        match self {
+            Response::Ack => Cow::Borrowed(b"ACK\n"),
+            Response::AckRange(first, last) => {
+                Cow::Owned(format!("ACK {} {}\n", first, last).into_bytes())
+            }
+            Response::Ok => Cow::Borrowed(b"OK\n"),
+            Response::FetchEnd {
+                next,
+                high_watermark,
+                log_start,
+            } => Cow::Owned(
+                format!(
+                    "OK next={} hw={} start={}\n",
+                    next, high_watermark, log_start
+                )
+                .into_bytes(),
+            ),
+            Response::Err { code, detail } => Cow::Owned(
+                format!("ERR {} retriable={} {}\n", code, code.retriable(), detail).into_bytes(),
+            ),
+            Response::Offset(offset) => Cow::Owned(format!("OK offset={}\n", offset).into_bytes()),
+            Response::Assignment { generation, topics } => {
+                let topics = if topics.is_empty() {
+                    "-".to_string()
+                } else {
+                    topics.join(",")
+                };
+                Cow::Owned(format!("OK gen={} topics={}\n", generation, topics).into_bytes())
+            }
+            Response::Hello(h) => {
+                let features = if h.features.is_empty() {
+                    "-".to_string()
+                } else {
+                    let names: Vec<_> = h.features.iter().map(Feature::as_str).collect();
+                    names.join(",")
+                };
+                Cow::Owned(
+                    format!(
+                        "OK version={} broker={} node={} features={}\n",
+                        h.version, h.broker, h.node_id, features
+                    )
+                    .into_bytes(),
+                )
+            }
+        }
+    }
+
+    /// Разбор строки ответа на стороне клиента; обратная операция к `as_bytes`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        let r = match line {
+            "ACK" => Response::Ack,
+            "OK" => Response::Ok,
+            _ => {
+                if let Some(rest) = line.strip_prefix("ERR ") {
+                    // ERR <CODE> retriable=<true|false> <detail>
+                    let mut it = rest.splitn(3, ' ');
+                    let code = ErrorCode::parse(it.next()?)?;
+                    it.next()?.strip_prefix("retriable=")?;
+                    let detail = it.next().unwrap_or("").to_string();
+                    Response::Err {
+                        code,
+                        detail: Cow::Owned(detail),
+                    }
+                } else if let Some(rest) = line.strip_prefix("ACK ") {
+                    let (first, last) = rest.split_once(' ')?;
+                    Response::AckRange(first.parse().ok()?, last.parse().ok()?)
+                } else if let Some(rest) = line.strip_prefix("OK ") {
+                    if let Some(next) = field(rest, "next") {
+                        Response::FetchEnd {
+                            next: next.parse().ok()?,
+                            high_watermark: field(rest, "hw")?.parse().ok()?,
+                            log_start: field(rest, "start")?.parse().ok()?,
+                        }
+                    } else if let Some(offset) = field(rest, "offset") {
+                        Response::Offset(offset.parse().ok()?)
+                    } else if let Some(version) = field(rest, "version") {
+                        // незнакомые возможности пропускаем: их мог добавить брокер новее
+                        let features = match field(rest, "features")? {
+                            "-" => Vec::new(),
+                            v => v.split(',').filter_map(Feature::parse).collect(),
+                        };
+                        Response::Hello(Hello {
+                            version: version.parse().ok()?,
+                            broker: field(rest, "broker")?.to_string(),
+                            node_id: field(rest, "node")?.to_string(),
+                            features,
+                        })
+                    } else {
+                        let topics = match field(rest, "topics")? {
+                            "-" => Vec::new(),
+                            v => v.split(',').map(str::to_string).collect(),
+                        };
+                        Response::Assignment {
+                            generation: field(rest, "gen")?.parse().ok()?,
+                            topics,
+                        }
+                    }
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(r)
+    }
+
+    pub fn err(code: ErrorCode, detail: impl Into<Cow<'static, str>>) -> Self {
+        Response::Err {
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn is_err(&self) -> bool {
+        matches!(self, Response::Err { .. })
+    }
+
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Response::Err { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.as_bytes();
+        f.write_str(String::from_utf8_lossy(&bytes).trim_end())
+    }
+}
+
+// `key=value` из ответа вида `OK key=value key=value`
+fn field<'a>(rest: &'a str, key: &str) -> Option<&'a str> {
+    rest.split_whitespace()
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// Запись в ответе FETCH: `<offset>\t<id>\t<payload>`,
+/// с HEADERS - `<offset>\t<id>\t<key=base64(value),...|->\t<payload>`.
+#[derive(Debug, Clone)]
+pub struct RecordLine {
+    pub offset: u64,
+    pub id: u64,
+    pub headers: Vec<(String, String)>,
+    pub payload: String,
+}
+
+impl RecordLine {
+    pub fn parse(line: &str, with_headers: bool) -> Option<Self> {
+        let mut it = line.splitn(if with_headers { 4 } else { 3 }, '\t');
+        let offset = it.next()?.parse::<u64>().ok()?;
+        let id = it.next()?.parse::<u64>().ok()?;
+        let headers = if with_headers {
+            parse_headers(it.next()?)?
+        } else {
+            Vec::new()
+        };
+        let payload = it.next()?.to_string();
+        Some(RecordLine {
+            offset,
+            id,
+            headers,
+            payload,
+        })
+    }
+}
+
+/// `key=base64(value),...`, `-` - заголовков нет.
+pub fn format_headers(headers: &[(String, String)]) -> String {
+    if headers.is_empty() {
+        return "-".to_string();
+    }
+    headers
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, STANDARD.encode(v.as_bytes())))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn parse_headers(v: &str) -> Option<Vec<(String, String)>> {
+    if v == "-" {
+        return Some(Vec::new());
+    }
+    v.split(',')
+        .map(|kv| {
+            let (k, v) = kv.split_once('=')?;
+            let value = String::from_utf8(STANDARD.decode(v).ok()?).ok()?;
+            Some((k.to_string(), value))
+        })
+        .collect()
+}
+
+/// Необязательные параметры PUB, идут перед payload:
+/// `PUB <topic> [AT=<unix-ms>] [DELAY=<ms>] [TTL=<ms>] [PRIO=<0-9>] <payload...>`
+#[derive(Default)]
+pub struct PubOpts {
+    pub at_ms: Option<u64>,
+    pub delay_ms: Option<u64>,
+    pub ttl_ms: Option<u64>,
+    pub prio: u8,
+}
+
+impl PubOpts {
+    // разбирает ведущие `KEY=VALUE`; возвращает остаток строки как payload
+    fn parse(mut rest: &str) -> Result<(Self, &str), &'static str> {
+        let mut opts = PubOpts::default();
+
+        loop {
+            let (tok, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            let Some((key, val)) = tok.split_once('=') else {
+                break;
+            };
+
+            match key {
+                "AT" => opts.at_ms = Some(val.parse().map_err(|_| "AT must be unix ms")?),
+                "DELAY" => opts.delay_ms = Some(val.parse().map_err(|_| "DELAY must be ms")?),
+                "TTL" => opts.ttl_ms = Some(val.parse().map_err(|_| "TTL must be ms")?),
+                "PRIO" => opts.prio = parse_prio(val).ok_or(BAD_PRIO)?,
+                _ => break,
+            }
+            rest = tail;
+        }
+
+        Ok((opts, rest))
+    }
+
+    /// Момент, раньше которого сообщение не должно попасть в топик.
+    pub fn not_before(&self, now_ms: u64) -> Option<u64> {
+        match (self.at_ms, self.delay_ms) {
+            (None, None) => None,
+            (at, delay) => Some(
+                at.unwrap_or(0)
+                    .max(delay.map(|d| now_ms.saturating_add(d)).unwrap_or(0)),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TopicKind {
+    Disk,
+    Memory,
+}
+
+impl TopicKind {
+    pub fn parse(v: &str) -> Option<Self> {
+        match v {
+            "disk" => Some(TopicKind::Disk),
+            "memory" => Some(TopicKind::Memory),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TopicKind::Disk => "disk",
+            TopicKind::Memory => "memory",
+        }
+    }
+}
+
+/// Как делить топики группы между ее участниками.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AssignStrategy {
+    Range,
+    RoundRobin,
+}
+
+impl AssignStrategy {
+    fn parse(v: &str) -> Option<Self> {
+        match v {
+            "range" => Some(AssignStrategy::Range),
+            "roundrobin" => Some(AssignStrategy::RoundRobin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AssignStrategy::Range => "range",
+            AssignStrategy::RoundRobin => "roundrobin",
+        }
+    }
+}
+
+/// Откуда читать группе, у которой нет offset-а или он вне лога.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResetPolicy {
+    Earliest,
+    Latest,
+    Error,
+}
+
+impl ResetPolicy {
+    pub fn parse(v: &str) -> Option<Self> {
+        match v {
+            "earliest" => Some(ResetPolicy::Earliest),
+            "latest" => Some(ResetPolicy::Latest),
+            "error" => Some(ResetPolicy::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
         match self {
-            Response::Ack => b"ACK\n",
-            Response::Nack => b"NACK\n",
-            Response::Ok => b"OK\n",
-            Response::ErrWal => b"ERR WAL\n",
-            Response::ErrBusy => b"ERR BUSY\n",
-            Response::ErrTimeout => b"ERR TIMEOUT\n",
-            Response::ErrTooLarge => b"ERR TOO_LARGE\n",
+            ResetPolicy::Earliest => "earliest",
+            ResetPolicy::Latest => "latest",
+            ResetPolicy::Error => "error",
+        }
+    }
+}
+
+/// Возможности соединения, о которых договариваются в HELLO.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    // FETCH и RECEIVE отдают заголовки и без флага HEADERS
+    Headers,
+    // ACK на PUB несет offset записи
+    Acks,
+    // команды с `#<id>`: выполняются параллельно, ответ приходит с тем же id
+    Ids,
+}
+
+impl Feature {
+    // MPUB есть всегда и о нем не договариваются
+    pub const ALL: [Feature; 3] = [Feature::Headers, Feature::Acks, Feature::Ids];
+
+    pub fn parse(v: &str) -> Option<Self> {
+        Feature::ALL.into_iter().find(|f| f.as_str() == v)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Feature::Headers => "headers",
+            Feature::Acks => "acks",
+            Feature::Ids => "ids",
+        }
+    }
+}
+
+/// `#<id> <строка>` - команда или итоговая строка ответа с id запроса.
+/// Строки данных ответа (FETCH, RECEIVE) идут перед итоговой без id, одним куском с ней.
+pub fn split_id(line: &str) -> Option<(u64, &str)> {
+    let (id, rest) = line.strip_prefix('#')?.split_once(' ')?;
+    Some((id.parse().ok()?, rest))
+}
+
+/// Ответ на HELLO:
+/// `OK version=<n> broker=<версия брокера> node=<node_id> features=<f,...|->`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    // согласованная версия протокола
+    pub version: u32,
+    pub broker: String,
+    pub node_id: String,
+    // то, что просил клиент и умеет брокер
+    pub features: Vec<Feature>,
+}
+
+/// Что делать с записью, когда очередь к worker заполнена:
+/// `reject`, `block:<ms>` или `pause`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    // сразу ERR QUEUE_FULL
+    Reject,
+    // ждать место в очереди до N мс, потом ERR QUEUE_FULL
+    Block(u64),
+    // ждать без срока; соединение в это время не читается - давление уходит в TCP
+    Pause,
+}
+
+impl Backpressure {
+    pub fn parse(v: &str) -> Option<Self> {
+        match v {
+            "reject" => Some(Backpressure::Reject),
+            "pause" => Some(Backpressure::Pause),
+            _ => v
+                .strip_prefix("block:")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .filter(|ms| *ms > 0)
+                .map(Backpressure::Block),
+        }
+    }
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backpressure::Reject => f.write_str("reject"),
+            Backpressure::Block(ms) => write!(f, "block:{}", ms),
+            Backpressure::Pause => f.write_str("pause"),
+        }
+    }
+}
+
+impl std::str::FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        Backpressure::parse(v)
+            .ok_or_else(|| format!("expected reject, block:<ms> or pause, got {:?}", v))
+    }
+}
+
+/// Куда SEEK переставляет offset группы.
+pub enum SeekTarget {
+    Offset(u64),
+    Earliest,
+    Latest,
+    // первая запись не старше заданного времени (unix ms)
+    Time(u64),
+}
+
+/// Параметры топика:
+/// `CREATE <topic> [TYPE=disk|memory] [CAPACITY=<n>] [TTL=<ms>] [MAXATTEMPTS=<n>] [DLQ=<topic>]`
+#[derive(Default)]
+pub struct TopicOpts {
+    pub kind: Option<TopicKind>,
+    pub capacity: Option<usize>,
+    pub ttl_ms: Option<u64>,
+    pub max_attempts: Option<u32>,
+    pub dlq: Option<String>,
+}
+
+impl TopicOpts {
+    fn parse<'a>(it: impl Iterator<Item = &'a str>) -> Result<Self, &'static str> {
+        let mut opts = TopicOpts::default();
+        for tok in it {
+            let (key, val) = tok.split_once('=').ok_or("options must be KEY=VALUE")?;
+            match key {
+                "TYPE" => {
+                    opts.kind = Some(TopicKind::parse(val).ok_or("TYPE must be disk or memory")?)
+                }
+                "CAPACITY" => {
+                    let n = val.parse::<usize>().ok().filter(|n| *n > 0);
+                    opts.capacity = Some(n.ok_or("CAPACITY must be a positive number")?)
+                }
+                "TTL" => opts.ttl_ms = Some(val.parse().map_err(|_| "TTL must be ms")?),
+                "MAXATTEMPTS" => {
+                    let n = val.parse::<u32>().ok().filter(|n| *n > 0);
+                    opts.max_attempts = Some(n.ok_or("MAXATTEMPTS must be a positive number")?)
+                }
+                "DLQ" if is_valid_topic(val) => opts.dlq = Some(val.to_string()),
+                "DLQ" => return Err(BAD_TOPIC),
+                _ => return Err("unknown CREATE option"),
+            }
         }
+        Ok(opts)
     }
 }
 
+/// Необязательные параметры FETCH:
+/// `[HEADERS] [PRIO=<0-9>] [MAXBYTES=<n>] [GROUP=<group>] [RESET=earliest|latest|error]`
+#[derive(Clone, Default)]
+pub struct FetchOpts {
+    pub headers: bool,
+    pub prio: u8,
+    pub max_bytes: Option<usize>,
+    pub group: Option<String>,
+    // важнее политики группы
+    pub reset: Option<ResetPolicy>,
+}
+
 pub enum Command {
     Ping,
     Pub {
         topic: String,
         payload: String,
+        opts: PubOpts,
+    },
+    MPub {
+        topic: String,
+        count: usize,
+        prio: u8,
     },
     Fetch {
+        topic: String,
+        // None - читать с offset-а группы
+        offset: Option<u64>,
+        limit: usize,
+        opts: FetchOpts,
+    },
+    Create {
+        topic: String,
+        opts: TopicOpts,
+    },
+    Receive {
+        topic: String,
+        visibility_ms: Option<u64>,
+        headers: bool,
+    },
+    Settle {
+        topic: String,
+        msg: MsgRef,
+        ack: bool,
+        reason: Option<String>,
+    },
+    Replay {
         topic: String,
         offset: u64,
         limit: usize,
     },
+    Sub {
+        pattern: String,
+    },
+    Unsub {
+        pattern: String,
+    },
+    Poll {
+        limit: usize,
+    },
+    Request {
+        topic: String,
+        timeout_ms: u64,
+        payload: String,
+    },
+    Reply {
+        corr_id: String,
+        payload: String,
+    },
+    Begin,
+    Commit,
+    Abort,
+    CommitOffset {
+        group: String,
+        topic: String,
+        offset: u64,
+        prio: u8,
+        generation: Option<u64>,
+    },
+    Join {
+        group: String,
+        member: String,
+        topics: Vec<String>,
+        session_timeout_ms: Option<u64>,
+        strategy: AssignStrategy,
+    },
+    Heartbeat {
+        group: String,
+        member: String,
+    },
+    Leave {
+        group: String,
+        member: String,
+    },
+    Lag {
+        group: Option<String>,
+    },
+    ConfigureGroup {
+        group: String,
+        reset: ResetPolicy,
+    },
+    Seek {
+        group: String,
+        topic: String,
+        target: SeekTarget,
+        prio: u8,
+    },
+    Topics,
+    // политика соединения при заполненной очереди, перекрывает настройку брокера
+    Backpressure(Backpressure),
+    // первая команда соединения; незнакомые брокеру возможности отброшены при разборе
+    Hello {
+        version: u32,
+        client: String,
+        features: Vec<Feature>,
+    },
+    // известная команда с неверными аргументами
+    Invalid {
+        line: String,
+        reason: &'static str,
+    },
     Unknown(String),
 }
 
 impl Command {
     pub fn parse(line: &str) -> Self {
         let line = line.trim();
+        // команда известна, но аргументы не разобрать - ответ BAD_REQUEST с причиной
+        let invalid = |reason: &'static str| Command::Invalid {
+            line: line.to_string(),
+            reason,
+        };
 
-        if line == "PING" {
-            return Command::Ping;
+        match line {
+            "PING" => return Command::Ping,
+            "BEGIN" => return Command::Begin,
+            "COMMIT" => return Command::Commit,
+            "ABORT" => return Command::Abort,
+            "LAG" => return Command::Lag { group: None },
+            "TOPICS" => return Command::Topics,
+            _ => {}
         }
 
         if let Some(rest) = line.strip_prefix("PUB ") {
-            // PUB <topic> <payload...>
+            // PUB <topic> [opts...] <payload...>
             let mut it = rest.splitn(2, ' ');
             let topic = it.next().unwrap_or("").trim();
-            let payload = it.next().unwrap_or("").to_string();
+            let rest = it.next().unwrap_or("");
 
             if topic.is_empty() {
-                return Command::Unknown(line.to_string());
+                return invalid("usage: PUB <topic> [opts...] <payload>");
+            }
+            if !is_valid_topic(topic) {
+                return invalid(BAD_TOPIC);
             }
 
+            let (opts, payload) = match PubOpts::parse(rest) {
+                Ok(parsed) => parsed,
+                Err(reason) => return invalid(reason),
+            };
+
             return Command::Pub {
                 topic: topic.to_string(),
-                payload,
+                payload: payload.to_string(),
+                opts,
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("MPUB ") {
+            // MPUB <topic> <count> [PRIO=<0-9>], далее <count> строк с payload
+            let mut it = rest.split_whitespace();
+            let topic = it.next().unwrap_or("").to_string();
+            let count = it.next().and_then(|v| v.parse::<usize>().ok());
+            let prio = match it.next() {
+                None => Some(0),
+                Some(tok) => tok.strip_prefix("PRIO=").and_then(parse_prio),
+            };
+
+            if topic.is_empty() || it.next().is_some() {
+                return invalid("usage: MPUB <topic> <count> [PRIO=<0-9>]");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+            let Some(count) = count.filter(|n| *n > 0) else {
+                return invalid("count must be a positive number");
+            };
+            let Some(prio) = prio else {
+                return invalid(BAD_PRIO);
             };
+
+            return Command::MPub { topic, count, prio };
         }
 
         if let Some(rest) = line.strip_prefix("FETCH ") {
-            // FETCH <topic> <offset> <limit>
+            // FETCH <topic> <offset|-> <limit> [HEADERS] [PRIO=<0-9>] [MAXBYTES=<n>]
+            //       [GROUP=<group>] [RESET=earliest|latest|error]
+            // `-` вместо offset-а - продолжить с offset-а, закоммиченного группой
             let mut it = rest.split_whitespace();
             let topic = it.next().unwrap_or("").to_string();
-            let offset = it.next().and_then(|v| v.parse::<u64>().ok());
+            let offset = match it.next() {
+                Some("-") => Some(None),
+                v => v.and_then(|v| v.parse::<u64>().ok()).map(Some),
+            };
             let limit = it.next().and_then(|v| v.parse::<usize>().ok());
 
-            if !topic.is_empty()
-                && let (Some(offset), Some(limit)) = (offset, limit)
-            {
-                return Command::Fetch {
+            if topic.is_empty() {
+                return invalid("usage: FETCH <topic> <offset|-> <limit> [opts...]");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+            let Some(offset) = offset else {
+                return invalid("offset must be a number or -");
+            };
+            let Some(limit) = limit else {
+                return invalid("limit must be a number");
+            };
+
+            let mut opts = FetchOpts::default();
+            for tok in it {
+                match tok.split_once('=') {
+                    None if tok == "HEADERS" => opts.headers = true,
+                    Some(("PRIO", v)) => match parse_prio(v) {
+                        Some(prio) => opts.prio = prio,
+                        None => return invalid(BAD_PRIO),
+                    },
+                    Some(("MAXBYTES", v)) => match v.parse::<usize>().ok().filter(|n| *n > 0) {
+                        Some(n) => opts.max_bytes = Some(n),
+                        None => return invalid("MAXBYTES must be a positive number"),
+                    },
+                    Some(("GROUP", v)) if !v.is_empty() => opts.group = Some(v.to_string()),
+                    Some(("RESET", v)) => match ResetPolicy::parse(v) {
+                        Some(reset) => opts.reset = Some(reset),
+                        None => return invalid(BAD_RESET),
+                    },
+                    _ => return invalid("unknown FETCH option"),
+                }
+            }
+
+            if offset.is_none() && opts.group.is_none() {
+                return invalid("offset - requires GROUP=<group>");
+            }
+
+            return Command::Fetch {
+                topic,
+                offset,
+                limit,
+                opts,
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("CREATE ") {
+            // CREATE <topic> [KEY=VALUE...]
+            let mut it = rest.split_whitespace();
+            let topic = it.next().unwrap_or("").to_string();
+
+            if topic.is_empty() {
+                return invalid("usage: CREATE <topic> [KEY=VALUE...]");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+
+            return match TopicOpts::parse(it) {
+                Ok(opts) => Command::Create { topic, opts },
+                Err(reason) => invalid(reason),
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("RECEIVE ") {
+            // RECEIVE <queue> [VT=<ms>] [HEADERS]
+            let mut it = rest.split_whitespace();
+            let topic = it.next().unwrap_or("").to_string();
+
+            let mut headers = false;
+            let mut visibility_ms = None;
+            for tok in it {
+                match tok {
+                    "HEADERS" => headers = true,
+                    _ => match tok.strip_prefix("VT=").and_then(|v| v.parse::<u64>().ok()) {
+                        Some(ms) => visibility_ms = Some(ms),
+                        None => return invalid("usage: RECEIVE <queue> [VT=<ms>] [HEADERS]"),
+                    },
+                }
+            }
+
+            if topic.is_empty() {
+                return invalid("usage: RECEIVE <queue> [VT=<ms>] [HEADERS]");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+
+            return Command::Receive {
+                topic,
+                visibility_ms,
+                headers,
+            };
+        }
+
+        for (prefix, ack) in [("ACKMSG ", true), ("NACKMSG ", false)] {
+            if let Some(rest) = line.strip_prefix(prefix) {
+                // ACKMSG <queue> <msg-ref> | NACKMSG <queue> <msg-ref> [reason...]
+                let mut it = rest.splitn(3, ' ');
+                let topic = it.next().unwrap_or("").to_string();
+                let msg = it.next().and_then(MsgRef::parse);
+                let reason = it
+                    .next()
+                    .map(|r| r.trim().to_string())
+                    .filter(|r| !r.is_empty());
+
+                if topic.is_empty() || (ack && reason.is_some()) {
+                    return invalid(if ack {
+                        "usage: ACKMSG <queue> <msg-ref>"
+                    } else {
+                        "usage: NACKMSG <queue> <msg-ref> [reason...]"
+                    });
+                }
+                if !is_valid_topic(&topic) {
+                    return invalid(BAD_TOPIC);
+                }
+                let Some(msg) = msg else {
+                    return invalid("bad message reference");
+                };
+
+                return Command::Settle {
                     topic,
-                    offset,
-                    limit,
+                    msg,
+                    ack,
+                    reason,
+                };
+            }
+        }
+
+        if let Some(rest) = line.strip_prefix("BACKPRESSURE ") {
+            // BACKPRESSURE reject|block:<ms>|pause
+            return match Backpressure::parse(rest.trim()) {
+                Some(policy) => Command::Backpressure(policy),
+                None => invalid("BACKPRESSURE expects reject, block:<ms> with ms > 0 or pause"),
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("HELLO ") {
+            // HELLO <version> <client-name> [features...]
+            let mut it = rest.split_whitespace();
+            let version = it.next().and_then(|v| v.parse::<u32>().ok());
+            let client = it.next();
+            if let (Some(version), Some(client)) = (version, client)
+                && version > 0
+            {
+                return Command::Hello {
+                    version,
+                    client: client.to_string(),
+                    features: it.filter_map(Feature::parse).collect(),
+                };
+            }
+            return invalid("usage: HELLO <version> <client-name> [features...]");
+        }
+
+        if let Some(rest) = line.strip_prefix("SUB ") {
+            // SUB <pattern>
+            let pattern = rest.trim();
+            if is_valid_pattern(pattern) {
+                return Command::Sub {
+                    pattern: pattern.to_string(),
                 };
             }
+            return invalid(BAD_PATTERN);
+        }
 
-            return Command::Unknown(line.to_string());
+        if let Some(rest) = line.strip_prefix("UNSUB ") {
+            // UNSUB <pattern>
+            let pattern = rest.trim();
+            if is_valid_pattern(pattern) {
+                return Command::Unsub {
+                    pattern: pattern.to_string(),
+                };
+            }
+            return invalid(BAD_PATTERN);
+        }
+
+        if let Some(rest) = line.strip_prefix("POLL ") {
+            // POLL <limit>
+            if let Ok(limit) = rest.trim().parse::<usize>() {
+                return Command::Poll { limit };
+            }
+            return invalid("limit must be a number");
+        }
+
+        if let Some(rest) = line.strip_prefix("REQUEST ") {
+            // REQUEST <topic> <timeout-ms> <payload...>
+            let mut it = rest.splitn(3, ' ');
+            let topic = it.next().unwrap_or("").trim().to_string();
+            let timeout_ms = it.next().and_then(|v| v.parse::<u64>().ok());
+            let payload = it.next().unwrap_or("").to_string();
+
+            if topic.is_empty() {
+                return invalid("usage: REQUEST <topic> <timeout-ms> <payload>");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+            let Some(timeout_ms) = timeout_ms else {
+                return invalid("timeout must be a number of ms");
+            };
+
+            return Command::Request {
+                topic,
+                timeout_ms,
+                payload,
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("REPLY ") {
+            // REPLY <correlation-id> <payload...>
+            let mut it = rest.splitn(2, ' ');
+            let corr_id = it.next().unwrap_or("").trim().to_string();
+            let payload = it.next().unwrap_or("").to_string();
+
+            if !corr_id.is_empty() {
+                return Command::Reply { corr_id, payload };
+            }
+
+            return invalid("usage: REPLY <correlation-id> <payload>");
+        }
+
+        if let Some(rest) = line.strip_prefix("REPLAY ") {
+            // REPLAY <dlq-topic> <offset> <limit>
+            let mut it = rest.split_whitespace();
+            let topic = it.next().unwrap_or("").to_string();
+            let offset = it.next().and_then(|v| v.parse::<u64>().ok());
+            let limit = it.next().and_then(|v| v.parse::<usize>().ok());
+
+            if topic.is_empty() || it.next().is_some() {
+                return invalid("usage: REPLAY <dlq-topic> <offset> <limit>");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+            let Some(offset) = offset else {
+                return invalid("offset must be a number");
+            };
+            let Some(limit) = limit else {
+                return invalid("limit must be a number");
+            };
+
+            return Command::Replay {
+                topic,
+                offset,
+                limit,
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("COMMITOFFSET ") {
+            // COMMITOFFSET <group> <topic> <offset> [PRIO=<0-9>] [GEN=<generation>]
+            let mut it = rest.split_whitespace();
+            let group = it.next().unwrap_or("").to_string();
+            let topic = it.next().unwrap_or("").to_string();
+            let offset = it.next().and_then(|v| v.parse::<u64>().ok());
+
+            if group.is_empty() || topic.is_empty() {
+                return invalid("usage: COMMITOFFSET <group> <topic> <offset> [opts...]");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+            let Some(offset) = offset else {
+                return invalid("offset must be a number");
+            };
+
+            let mut prio = 0;
+            let mut generation = None;
+            for tok in it {
+                match tok.split_once('=') {
+                    Some(("PRIO", v)) => match parse_prio(v) {
+                        Some(p) => prio = p,
+                        None => return invalid(BAD_PRIO),
+                    },
+                    Some(("GEN", v)) => match v.parse::<u64>() {
+                        Ok(g) => generation = Some(g),
+                        Err(_) => return invalid("GEN must be a number"),
+                    },
+                    _ => return invalid("unknown COMMITOFFSET option"),
+                }
+            }
+
+            return Command::CommitOffset {
+                group,
+                topic,
+                offset,
+                prio,
+                generation,
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("JOIN ") {
+            // JOIN <group> <member> <topic>[,<topic>...] [SESSION=<ms>] [STRATEGY=range|roundrobin]
+            let mut it = rest.split_whitespace();
+            let group = it.next().unwrap_or("").to_string();
+            let member = it.next().unwrap_or("").to_string();
+            let topics: Vec<String> = it
+                .next()
+                .unwrap_or("")
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(str::to_string)
+                .collect();
+
+            if group.is_empty() || member.is_empty() || topics.is_empty() {
+                return invalid("usage: JOIN <group> <member> <topic>[,<topic>...] [opts...]");
+            }
+            if !topics.iter().all(|t| is_valid_topic(t)) {
+                return invalid(BAD_TOPIC);
+            }
+
+            let mut session_timeout_ms = None;
+            let mut strategy = AssignStrategy::Range;
+            for tok in it {
+                match tok.split_once('=') {
+                    Some(("SESSION", v)) => match v.parse::<u64>().ok().filter(|ms| *ms > 0) {
+                        Some(ms) => session_timeout_ms = Some(ms),
+                        None => return invalid("SESSION must be a positive number of ms"),
+                    },
+                    Some(("STRATEGY", v)) => match AssignStrategy::parse(v) {
+                        Some(s) => strategy = s,
+                        None => return invalid("STRATEGY must be range or roundrobin"),
+                    },
+                    _ => return invalid("unknown JOIN option"),
+                }
+            }
+
+            return Command::Join {
+                group,
+                member,
+                topics,
+                session_timeout_ms,
+                strategy,
+            };
+        }
+
+        for (prefix, leave) in [("HEARTBEAT ", false), ("LEAVE ", true)] {
+            let Some(rest) = line.strip_prefix(prefix) else {
+                continue;
+            };
+            // HEARTBEAT <group> <member> / LEAVE <group> <member>
+            let mut it = rest.split_whitespace();
+            if let (Some(group), Some(member), None) = (it.next(), it.next(), it.next()) {
+                let (group, member) = (group.to_string(), member.to_string());
+                return if leave {
+                    Command::Leave { group, member }
+                } else {
+                    Command::Heartbeat { group, member }
+                };
+            }
+            return invalid(if leave {
+                "usage: LEAVE <group> <member>"
+            } else {
+                "usage: HEARTBEAT <group> <member>"
+            });
+        }
+
+        if let Some(rest) = line.strip_prefix("GROUP ") {
+            // GROUP <group> RESET=earliest|latest|error
+            let mut it = rest.split_whitespace();
+            let group = it.next().unwrap_or("").to_string();
+            let reset = it.next().and_then(|tok| tok.strip_prefix("RESET="));
+
+            if group.is_empty() || it.next().is_some() {
+                return invalid("usage: GROUP <group> RESET=earliest|latest|error");
+            }
+            let Some(reset) = reset.and_then(ResetPolicy::parse) else {
+                return invalid(BAD_RESET);
+            };
+
+            return Command::ConfigureGroup { group, reset };
+        }
+
+        if let Some(rest) = line.strip_prefix("SEEK ") {
+            // SEEK <group> <topic> <offset|earliest|latest|TS=<unix-ms>> [PRIO=<0-9>]
+            let mut it = rest.split_whitespace();
+            let group = it.next().unwrap_or("").to_string();
+            let topic = it.next().unwrap_or("").to_string();
+            let target = match it.next() {
+                Some("earliest") => Some(SeekTarget::Earliest),
+                Some("latest") => Some(SeekTarget::Latest),
+                Some(tok) => match tok.strip_prefix("TS=") {
+                    Some(ts) => ts.parse::<u64>().ok().map(SeekTarget::Time),
+                    None => tok.parse::<u64>().ok().map(SeekTarget::Offset),
+                },
+                None => None,
+            };
+            let prio = match it.next() {
+                None => Some(0),
+                Some(tok) => tok.strip_prefix("PRIO=").and_then(parse_prio),
+            };
+
+            if group.is_empty() || topic.is_empty() || it.next().is_some() {
+                return invalid("usage: SEEK <group> <topic> <target> [PRIO=<0-9>]");
+            }
+            if !is_valid_topic(&topic) {
+                return invalid(BAD_TOPIC);
+            }
+            let Some(target) = target else {
+                return invalid("SEEK target must be an offset, earliest, latest or TS=<unix-ms>");
+            };
+            let Some(prio) = prio else {
+                return invalid(BAD_PRIO);
+            };
+
+            return Command::Seek {
+                group,
+                topic,
+                target,
+                prio,
+            };
+        }
+
+        if let Some(rest) = line.strip_prefix("LAG ") {
+            // LAG [group]
+            let group = rest.trim();
+            if !group.is_empty() && !group.contains(char::is_whitespace) {
+                return Command::Lag {
+                    group: Some(group.to_string()),
+                };
+            }
+            return invalid("usage: LAG [group]");
+        }
+
+        // известная команда без обязательных аргументов
+        let verb = line.split_whitespace().next().unwrap_or("");
+        if VERBS_WITH_ARGS.contains(&verb) {
+            return invalid("missing arguments");
         }
 
         Command::Unknown(line.to_string())
     }
 }
+
+/// Строка команды без перевода строки; `Command::parse` разбирает ее обратно.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Ping => f.write_str("PING"),
+            Command::Pub {
+                topic,
+                payload,
+                opts,
+            } => {
+                write!(f, "PUB {}", topic)?;
+                opt(f, "AT", opts.at_ms)?;
+                opt(f, "DELAY", opts.delay_ms)?;
+                opt(f, "TTL", opts.ttl_ms)?;
+                prio_opt(f, opts.prio)?;
+                write!(f, " {}", payload)
+            }
+            Command::MPub { topic, count, prio } => {
+                write!(f, "MPUB {} {}", topic, count)?;
+                prio_opt(f, *prio)
+            }
+            Command::Fetch {
+                topic,
+                offset,
+                limit,
+                opts,
+            } => {
+                match offset {
+                    Some(offset) => write!(f, "FETCH {} {} {}", topic, offset, limit)?,
+                    None => write!(f, "FETCH {} - {}", topic, limit)?,
+                }
+                if opts.headers {
+                    f.write_str(" HEADERS")?;
+                }
+                prio_opt(f, opts.prio)?;
+                opt(f, "MAXBYTES", opts.max_bytes)?;
+                opt(f, "GROUP", opts.group.as_deref())?;
+                opt(f, "RESET", opts.reset.map(|r| r.as_str()))
+            }
+            Command::Create { topic, opts } => {
+                write!(f, "CREATE {}", topic)?;
+                opt(f, "TYPE", opts.kind.map(|k| k.as_str()))?;
+                opt(f, "CAPACITY", opts.capacity)?;
+                opt(f, "TTL", opts.ttl_ms)?;
+                opt(f, "MAXATTEMPTS", opts.max_attempts)?;
+                opt(f, "DLQ", opts.dlq.as_deref())
+            }
+            Command::Receive {
+                topic,
+                visibility_ms,
+                headers,
+            } => {
+                write!(f, "RECEIVE {}", topic)?;
+                opt(f, "VT", *visibility_ms)?;
+                if *headers {
+                    f.write_str(" HEADERS")?;
+                }
+                Ok(())
+            }
+            Command::Settle {
+                topic,
+                msg,
+                ack,
+                reason,
+            } => {
+                let verb = if *ack { "ACKMSG" } else { "NACKMSG" };
+                write!(f, "{} {} {}", verb, topic, msg)?;
+                match reason {
+                    Some(reason) => write!(f, " {}", reason),
+                    None => Ok(()),
+                }
+            }
+            Command::Replay {
+                topic,
+                offset,
+                limit,
+            } => write!(f, "REPLAY {} {} {}", topic, offset, limit),
+            Command::Sub { pattern } => write!(f, "SUB {}", pattern),
+            Command::Unsub { pattern } => write!(f, "UNSUB {}", pattern),
+            Command::Poll { limit } => write!(f, "POLL {}", limit),
+            Command::Request {
+                topic,
+                timeout_ms,
+                payload,
+            } => write!(f, "REQUEST {} {} {}", topic, timeout_ms, payload),
+            Command::Reply { corr_id, payload } => write!(f, "REPLY {} {}", corr_id, payload),
+            Command::Begin => f.write_str("BEGIN"),
+            Command::Commit => f.write_str("COMMIT"),
+            Command::Abort => f.write_str("ABORT"),
+            Command::CommitOffset {
+                group,
+                topic,
+                offset,
+                prio,
+                generation,
+            } => {
+                write!(f, "COMMITOFFSET {} {} {}", group, topic, offset)?;
+                prio_opt(f, *prio)?;
+                opt(f, "GEN", *generation)
+            }
+            Command::Join {
+                group,
+                member,
+                topics,
+                session_timeout_ms,
+                strategy,
+            } => {
+                write!(f, "JOIN {} {} {}", group, member, topics.join(","))?;
+                opt(f, "SESSION", *session_timeout_ms)?;
+                if *strategy != AssignStrategy::Range {
+                    opt(f, "STRATEGY", Some(strategy.as_str()))?;
+                }
+                Ok(())
+            }
+            Command::Heartbeat { group, member } => write!(f, "HEARTBEAT {} {}", group, member),
+            Command::Leave { group, member } => write!(f, "LEAVE {} {}", group, member),
+            Command::Lag { group: None } => f.write_str("LAG"),
+            Command::Lag { group: Some(group) } => write!(f, "LAG {}", group),
+            Command::ConfigureGroup { group, reset } => {
+                write!(f, "GROUP {} RESET={}", group, reset.as_str())
+            }
+            Command::Seek {
+                group,
+                topic,
+                target,
+                prio,
+            } => {
+                write!(f, "SEEK {} {} ", group, topic)?;
+                match target {
+                    SeekTarget::Offset(offset) => write!(f, "{}", offset)?,
+                    SeekTarget::Earliest => f.write_str("earliest")?,
+                    SeekTarget::Latest => f.write_str("latest")?,
+                    SeekTarget::Time(ts) => write!(f, "TS={}", ts)?,
+                }
+                prio_opt(f, *prio)
+            }
+            Command::Topics => f.write_str("TOPICS"),
+            Command::Backpressure(policy) => write!(f, "BACKPRESSURE {}", policy),
+            Command::Hello {
+                version,
+                client,
+                features,
+            } => {
+                write!(f, "HELLO {} {}", version, client)?;
+                for feature in features {
+                    write!(f, " {}", feature.as_str())?;
+                }
+                Ok(())
+            }
+            Command::Invalid { line, .. } | Command::Unknown(line) => f.write_str(line),
+        }
+    }
+}
+
+// ` KEY=value`, если значение задано
+fn opt(f: &mut fmt::Formatter<'_>, key: &str, v: Option<impl fmt::Display>) -> fmt::Result {
+    match v {
+        Some(v) => write!(f, " {}={}", key, v),
+        None => Ok(()),
+    }
+}
+
+// уровень 0 - по умолчанию, его не пишем
+fn prio_opt(f: &mut fmt::Formatter<'_>, prio: u8) -> fmt::Result {
+    opt(f, "PRIO", (prio != 0).then_some(prio))
+}
+
+// `orders.*.created`, `orders.>`: непустые токены, `>` только последним
+/// Имя топика - это имя каталога в data_dir: без разделителей пути, `..`
+/// и служебного префикса `__` (`__tx`, `__schedule`, `__groups`).
+pub fn is_valid_topic(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && !name.starts_with('.')
+        && !name.starts_with("__")
+        && !name.contains("..")
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+fn is_valid_pattern(pattern: &str) -> bool {
+    if pattern.is_empty() || pattern.contains(char::is_whitespace) {
+        return false;
+    }
+    let tokens: Vec<&str> = pattern.split('.').collect();
+    tokens
+        .iter()
+        .enumerate()
+        .all(|(i, t)| !t.is_empty() && (*t != ">" || i + 1 == tokens.len()))
+}
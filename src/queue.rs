@@ -1,23 +1,219 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
 use tokio::sync::{
-    mpsc::{Sender, error::TrySendError},
+    mpsc::{
+        Sender,
+        error::{SendTimeoutError, TrySendError},
+    },
     oneshot,
 };
 
-use crate::{stats::Stats, wal::WalRecord};
+use crate::{
+    membership::Assignment,
+    protocol::{
+        AssignStrategy, Backpressure, MsgRef, ResetPolicy, SeekTarget, TopicKind, TopicOpts,
+    },
+    stats::Stats,
+    wal::WalRecord,
+};
+
+/// Параметры одиночной записи из PUB.
+pub struct ProduceOpts {
+    pub not_before: Option<u64>,
+    pub ttl_ms: Option<u64>,
+    pub prio: u8,
+    pub headers: Vec<(String, String)>,
+}
 
 pub enum Request {
     Produce {
         topic: String,
         id: u64,
         msg: String,
-        committed: oneshot::Sender<()>,
+        opts: ProduceOpts,
+        // offset записи; None - отложенная запись, offset появится при выпуске из расписания
+        committed: oneshot::Sender<Result<Option<u64>, MemoryTopic>>,
+    },
+    Tick,
+    ProduceBatch {
+        topic: String,
+        prio: u8,
+        records: Vec<(u64, String)>,
+        committed: oneshot::Sender<Result<(u64, u64), MemoryTopic>>,
     },
     Fetch {
+        topic: String,
+        // None - с offset-а группы
+        from: Option<u64>,
+        limit: usize,
+        // worker перестает читать, набрав примерно столько байт ответа
+        max_bytes: usize,
+        prio: u8,
+        group: Option<String>,
+        reset: Option<ResetPolicy>,
+        reply: oneshot::Sender<Result<Fetched, FetchError>>,
+    },
+    Receive {
+        topic: String,
+        visibility_ms: u64,
+        reply: oneshot::Sender<Result<Option<Leased>, MemoryTopic>>,
+    },
+    Settle {
+        topic: String,
+        msg: MsgRef,
+        ack: bool,
+        reason: Option<String>,
+        reply: oneshot::Sender<bool>,
+    },
+    Replay {
         topic: String,
         from: u64,
         limit: usize,
-        reply: oneshot::Sender<Vec<WalRecord>>,
+        reply: oneshot::Sender<Vec<Replayed>>,
     },
+    Poll {
+        patterns: Vec<String>,
+        // позиции чтения по топику и уровню приоритета
+        cursors: HashMap<(String, u8), u64>,
+        // сдвиг первого топика, чтобы первые по имени не забирали весь limit
+        start: usize,
+        limit: usize,
+        reply: oneshot::Sender<Vec<(String, u8, WalRecord)>>,
+    },
+    Commit {
+        records: Vec<TxRecord>,
+        committed: oneshot::Sender<u64>,
+    },
+    CommitOffset {
+        group: String,
+        topic: String,
+        prio: u8,
+        offset: u64,
+        generation: Option<u64>,
+        reply: oneshot::Sender<CommitResult>,
+    },
+    Join {
+        group: String,
+        member: String,
+        topics: Vec<String>,
+        session_timeout_ms: u64,
+        strategy: AssignStrategy,
+        // None - стратегия не совпадает с группой
+        reply: oneshot::Sender<Option<Assignment>>,
+    },
+    Heartbeat {
+        group: String,
+        member: String,
+        // None - участника нет в группе
+        reply: oneshot::Sender<Option<Assignment>>,
+    },
+    Leave {
+        group: String,
+        member: String,
+        reply: oneshot::Sender<bool>,
+    },
+    Lag {
+        group: Option<String>,
+        reply: oneshot::Sender<Vec<Lag>>,
+    },
+    // все топики: дисковые и в памяти
+    Topics {
+        reply: oneshot::Sender<Vec<(String, TopicKind)>>,
+    },
+    ConfigureGroup {
+        group: String,
+        reset: ResetPolicy,
+        reply: oneshot::Sender<()>,
+    },
+    Seek {
+        group: String,
+        topic: String,
+        prio: u8,
+        target: SeekTarget,
+        reply: oneshot::Sender<SeekResult>,
+    },
+    CreateTopic {
+        topic: String,
+        opts: TopicOpts,
+        reply: oneshot::Sender<bool>,
+    },
+}
+
+/// Ответ FETCH: записи и границы лога на момент чтения.
+#[derive(Default)]
+pub struct Fetched {
+    // offset, с которого на самом деле читали (после сброса)
+    pub from: u64,
+    pub records: Vec<WalRecord>,
+    pub start_offset: u64,
+    pub next_offset: u64,
+}
+
+/// Запись транзакции, накопленная между BEGIN и COMMIT.
+pub struct TxRecord {
+    pub topic: String,
+    pub id: u64,
+    pub msg: String,
+    pub prio: u8,
+}
+
+pub enum FetchError {
+    UnknownTopic,
+    // PRIO>0 у эфемерного топика
+    MemoryTopic,
+    // offset вне лога, а политика сброса - error
+    OffsetOutOfRange,
+}
+
+/// Выданная RECEIVE запись: ссылка для ACKMSG, сама запись и номер попытки.
+pub type Leased = (MsgRef, WalRecord, u32);
+
+/// У эфемерного топика нет уровней приоритета и аренд RECEIVE.
+#[derive(Debug)]
+pub struct MemoryTopic;
+
+pub enum SeekResult {
+    Seeked(u64),
+    UnknownTopic,
+    OutOfRange,
+    // у группы есть живые участники
+    GroupActive,
+}
+
+pub enum CommitResult {
+    Committed,
+    UnknownTopic,
+    // offset за high watermark
+    OutOfRange,
+    // коммит от поколения группы, которое уже сменилось
+    StaleGeneration,
+}
+
+/// Отставание группы по одному логу топика.
+pub struct Lag {
+    pub group: String,
+    pub topic: String,
+    pub prio: u8,
+    pub committed: u64,
+    // None - топика больше нет (например, эфемерный после рестарта)
+    pub high_watermark: Option<u64>,
+    pub since_commit_ms: u64,
+}
+
+impl Lag {
+    pub fn lag(&self) -> Option<u64> {
+        self.high_watermark
+            .map(|hw| hw.saturating_sub(self.committed))
+    }
+}
+
+/// Запись, возвращенная REPLAY из dead-letter топика в исходный.
+pub struct Replayed {
+    pub dlq_offset: u64,
+    pub topic: String,
+    pub offset: u64,
 }
 
 pub enum EnqueueResult {
@@ -26,22 +222,104 @@ pub enum EnqueueResult {
     Closed,
 }
 
-pub fn try_enqueue(
-    tx: &Sender<Request>,
+/// Очередь к worker глазами одного соединения: с его политикой на случай,
+/// когда очередь заполнена. Остальные запросы идут через обычный `send`.
+#[derive(Clone)]
+pub struct QueueTx {
+    tx: Sender<Request>,
+    pub backpressure: Backpressure,
+}
+
+impl QueueTx {
+    pub fn new(tx: Sender<Request>, backpressure: Backpressure) -> Self {
+        QueueTx { tx, backpressure }
+    }
+
+    /// Запись в очередь по политике соединения; время ожидания попадает в статистику.
+    pub async fn send_write(&self, stats: &Stats, req: Request) -> Result<(), TrySendError<()>> {
+        let req = match self.tx.try_send(req) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(req)) => req,
+            Err(TrySendError::Closed(_)) => return Err(TrySendError::Closed(())),
+        };
+
+        let started = Instant::now();
+        let res = match self.backpressure {
+            Backpressure::Reject => return Err(TrySendError::Full(())),
+            Backpressure::Block(ms) => {
+                match self.tx.send_timeout(req, Duration::from_millis(ms)).await {
+                    Ok(()) => Ok(()),
+                    Err(SendTimeoutError::Timeout(_)) => Err(TrySendError::Full(())),
+                    Err(SendTimeoutError::Closed(_)) => Err(TrySendError::Closed(())),
+                }
+            }
+            Backpressure::Pause => self
+                .tx
+                .send(req)
+                .await
+                .map_err(|_| TrySendError::Closed(())),
+        };
+        let waited = started.elapsed();
+        stats.add_blocked(waited);
+        tracing::debug!(
+            policy = %self.backpressure,
+            waited_ms = waited.as_millis() as u64,
+            "waited for queue"
+        );
+        res
+    }
+}
+
+impl Deref for QueueTx {
+    type Target = Sender<Request>;
+
+    fn deref(&self) -> &Sender<Request> {
+        &self.tx
+    }
+}
+
+pub async fn enqueue(
+    tx: &QueueTx,
     stats: &Stats,
     topic: String,
     msg: String,
-    committed: oneshot::Sender<()>,
+    opts: ProduceOpts,
+    committed: oneshot::Sender<Result<Option<u64>, MemoryTopic>>,
 ) -> EnqueueResult {
     let id = stats.new_id();
     let req = Request::Produce {
         topic,
         id,
         msg,
+        opts,
+        committed,
+    };
+
+    match tx.send_write(stats, req).await {
+        Ok(_) => EnqueueResult::Enqueued(id),
+        Err(TrySendError::Full(_)) => EnqueueResult::Full,
+        Err(TrySendError::Closed(_)) => EnqueueResult::Closed,
+    }
+}
+
+pub async fn enqueue_batch(
+    tx: &QueueTx,
+    stats: &Stats,
+    topic: String,
+    prio: u8,
+    msgs: Vec<String>,
+    committed: oneshot::Sender<Result<(u64, u64), MemoryTopic>>,
+) -> EnqueueResult {
+    let records: Vec<(u64, String)> = msgs.into_iter().map(|m| (stats.new_id(), m)).collect();
+    let id = records.first().map(|(id, _)| *id).unwrap_or_default();
+    let req = Request::ProduceBatch {
+        topic,
+        prio,
+        records,
         committed,
     };
 
-    match tx.try_send(req) {
+    match tx.send_write(stats, req).await {
         Ok(_) => EnqueueResult::Enqueued(id),
         Err(TrySendError::Full(_)) => EnqueueResult::Full,
         Err(TrySendError::Closed(_)) => EnqueueResult::Closed,
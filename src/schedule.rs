@@ -0,0 +1,296 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions, rename},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// после стольких доставок лог переписывается только с ожидающими записями
+const COMPACT_AFTER_DONE: usize = 1024;
+
+#[derive(Clone)]
+pub struct ScheduledMsg {
+    pub sid: u64,
+    pub due_ms: u64,
+    pub topic: String,
+    pub id: u64,
+    pub msg: String,
+    pub ttl_ms: Option<u64>,
+    pub prio: u8,
+}
+
+/// Лог отложенных сообщений.
+/// Формат строк:
+///   `S\t<sid>\t<due_ms>\t<topic>\t<id>\t<payload_b64>[\t<ttl_ms|->[\t<prio>]]` - запланировано
+///   `D\t<sid>` - перенесено в WAL топика
+pub struct Schedule {
+    file: File,
+    path: PathBuf,
+    next_sid: u64,
+    pending: BTreeMap<(u64, u64), ScheduledMsg>,
+    // забраны take_due, но `D` еще не записан: из лога их стирать нельзя
+    in_flight: BTreeMap<u64, ScheduledMsg>,
+    done_since_compact: usize,
+}
+
+impl Schedule {
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join("schedule.log");
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let mut s = Schedule {
+            file,
+            path,
+            next_sid: 0,
+            pending: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            done_since_compact: 0,
+        };
+
+        s.recover()?;
+        Ok(s)
+    }
+
+    fn recover(&mut self) -> std::io::Result<()> {
+        let f = OpenOptions::new().read(true).open(&self.path)?;
+        let reader = BufReader::new(f);
+
+        let mut by_sid: BTreeMap<u64, ScheduledMsg> = BTreeMap::new();
+        let mut valid_end: u64 = 0;
+
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+
+            let mut it = line.split('\t');
+            match it.next() {
+                Some("S") => {
+                    let Some(m) = parse_scheduled(it) else { break };
+                    self.next_sid = self.next_sid.max(m.sid + 1);
+                    by_sid.insert(m.sid, m);
+                }
+                Some("D") => {
+                    let Some(sid) = it.next().and_then(|v| v.parse::<u64>().ok()) else {
+                        break;
+                    };
+                    by_sid.remove(&sid);
+                    self.done_since_compact += 1;
+                }
+                _ => break,
+            }
+
+            valid_end += (line.len() + 1) as u64;
+        }
+
+        // обрезаем битый хвост
+        self.file.set_len(valid_end)?;
+        self.file.seek(SeekFrom::End(0))?;
+
+        self.pending = by_sid
+            .into_values()
+            .map(|m| ((m.due_ms, m.sid), m))
+            .collect();
+
+        Ok(())
+    }
+
+    pub fn add(
+        &mut self,
+        due_ms: u64,
+        topic: &str,
+        id: u64,
+        msg: &str,
+        ttl_ms: Option<u64>,
+        prio: u8,
+    ) -> std::io::Result<u64> {
+        let m = ScheduledMsg {
+            sid: self.next_sid,
+            due_ms,
+            topic: topic.to_string(),
+            id,
+            msg: msg.to_string(),
+            ttl_ms,
+            prio,
+        };
+        writeln!(self.file, "{}", format_scheduled(&m))?;
+        self.file.sync_all()?;
+
+        self.next_sid += 1;
+        let sid = m.sid;
+        self.pending.insert((due_ms, sid), m);
+        Ok(sid)
+    }
+
+    /// Забирает из очереди все сообщения со сроком `<= now_ms`.
+    /// В логе они остаются до `mark_delivered` или `restore`.
+    pub fn take_due(&mut self, now_ms: u64) -> Vec<ScheduledMsg> {
+        let rest = self.pending.split_off(&(now_ms.saturating_add(1), 0));
+        let due = std::mem::replace(&mut self.pending, rest);
+        due.into_values()
+            .inspect(|m| {
+                self.in_flight.insert(m.sid, m.clone());
+            })
+            .collect()
+    }
+
+    /// Возвращает сообщение обратно, если перенести его в WAL не удалось.
+    pub fn restore(&mut self, m: ScheduledMsg) {
+        self.in_flight.remove(&m.sid);
+        self.pending.insert((m.due_ms, m.sid), m);
+    }
+
+    pub fn mark_delivered(&mut self, sid: u64) -> std::io::Result<()> {
+        writeln!(self.file, "D\t{}", sid)?;
+        self.file.sync_all()?;
+        self.in_flight.remove(&sid);
+        self.done_since_compact += 1;
+
+        if self.pending.is_empty() && self.in_flight.is_empty() {
+            self.file.set_len(0)?;
+            self.file.seek(SeekFrom::End(0))?;
+            self.done_since_compact = 0;
+        } else if self.done_since_compact >= COMPACT_AFTER_DONE {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn compact(&mut self) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("log.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            for m in self.pending.values().chain(self.in_flight.values()) {
+                writeln!(f, "{}", format_scheduled(m))?;
+            }
+            f.sync_all()?;
+        }
+        rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.path)?;
+        self.done_since_compact = 0;
+        Ok(())
+    }
+}
+
+fn parse_scheduled<'a>(mut it: impl Iterator<Item = &'a str>) -> Option<ScheduledMsg> {
+    let sid = it.next()?.parse::<u64>().ok()?;
+    let due_ms = it.next()?.parse::<u64>().ok()?;
+    let topic = it.next()?.to_string();
+    let id = it.next()?.parse::<u64>().ok()?;
+    let bytes = STANDARD.decode(it.next()?).ok()?;
+    let msg = String::from_utf8(bytes).ok()?;
+    let ttl_ms = match it.next() {
+        Some("-") | None => None,
+        Some(v) => Some(v.parse::<u64>().ok()?),
+    };
+    let prio = match it.next() {
+        Some(v) => v.parse::<u8>().ok()?,
+        None => 0,
+    };
+    if it.next().is_some() || topic.is_empty() {
+        return None;
+    }
+
+    Some(ScheduledMsg {
+        sid,
+        due_ms,
+        topic,
+        id,
+        msg,
+        ttl_ms,
+        prio,
+    })
+}
+
+fn format_scheduled(m: &ScheduledMsg) -> String {
+    let mut line = format!(
+        "S\t{}\t{}\t{}\t{}\t{}",
+        m.sid,
+        m.due_ms,
+        m.topic,
+        m.id,
+        STANDARD.encode(m.msg.as_bytes())
+    );
+    match (m.ttl_ms, m.prio) {
+        (None, 0) => {}
+        (Some(ttl), 0) => line.push_str(&format!("\t{}", ttl)),
+        (None, prio) => line.push_str(&format!("\t-\t{}", prio)),
+        (Some(ttl), prio) => line.push_str(&format!("\t{}\t{}", ttl, prio)),
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sids(msgs: &[ScheduledMsg]) -> Vec<u64> {
+        msgs.iter().map(|m| m.sid).collect()
+    }
+
+    #[test]
+    fn take_due_returns_only_due_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = Schedule::open(dir.path()).unwrap();
+        let late = s.add(200, "t", 1, "late", None, 0).unwrap();
+        let early = s.add(100, "t", 2, "early", Some(50), 3).unwrap();
+
+        assert!(s.take_due(99).is_empty());
+        let due = s.take_due(100);
+        assert_eq!(sids(&due), [early]);
+        assert_eq!((due[0].ttl_ms, due[0].prio), (Some(50), 3));
+        assert_eq!(sids(&s.take_due(1000)), [late]);
+        assert!(s.take_due(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn taken_but_undelivered_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = Schedule::open(dir.path()).unwrap();
+        let a = s.add(100, "t", 1, "a", None, 0).unwrap();
+        let b = s.add(100, "t", 2, "b", None, 0).unwrap();
+
+        // доставлено только первое из пачки, потом падение
+        assert_eq!(sids(&s.take_due(100)), [a, b]);
+        s.mark_delivered(a).unwrap();
+        drop(s);
+
+        let mut s = Schedule::open(dir.path()).unwrap();
+        assert_eq!(s.pending_len(), 1);
+        let due = s.take_due(100);
+        assert_eq!(sids(&due), [b]);
+        assert_eq!(due[0].msg, "b");
+    }
+
+    #[test]
+    fn restored_message_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut s = Schedule::open(dir.path()).unwrap();
+        let a = s.add(100, "t", 1, "a", None, 0).unwrap();
+        let b = s.add(100, "t", 2, "b", None, 0).unwrap();
+
+        // запись в WAL не удалась: сообщение возвращается в очередь
+        let mut due = s.take_due(100);
+        s.restore(due.pop().unwrap());
+        s.mark_delivered(a).unwrap();
+        drop(s);
+
+        let mut s = Schedule::open(dir.path()).unwrap();
+        assert_eq!(sids(&s.take_due(100)), [b]);
+    }
+}
@@ -1,48 +1,101 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::{
     net::TcpListener,
     sync::mpsc::{self},
+    task::JoinHandle,
 };
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
+use crate::inbox::Inboxes;
+use crate::ingress;
+use crate::init::Settings;
+use crate::protocol::{ErrorCode, Response};
 use crate::stats::Stats;
 use crate::{config::AppConfig, init::Shutdown, worker};
-use crate::{ingress, protocol::Response};
 
 use crate::queue::Request;
 
 pub struct Service {
     pub bind_addr: String,
-    pub max_connections: usize,
     pub data_dir: String,
+    pub queue_capacity: usize,
+    // отдается клиентам в ответе на HELLO
+    pub node_id: Arc<str>,
+    // max_connections, drain timeout и прочее, что меняется по SIGHUP
+    pub settings: Settings,
+}
+
+/// Worker и ticker; очередь к worker закрывается, когда отпущены все копии `tx`.
+pub(crate) struct Core {
+    pub tx: mpsc::Sender<Request>,
+    pub stats: Arc<Stats>,
+    worker: JoinHandle<()>,
+    ticker: JoinHandle<()>,
 }
 
 impl Service {
-    pub fn new(conf: &AppConfig) -> Self {
+    pub fn new(conf: &AppConfig, settings: Settings) -> Self {
         Service {
             bind_addr: conf.bind_addr.clone(),
-            max_connections: conf.max_connections,
             data_dir: conf.data_dir.clone(),
+            queue_capacity: conf.queue_capacity,
+            node_id: conf.node_id.as_str().into(),
+            settings,
         }
     }
 
     pub async fn start(&self, shutdown: &Shutdown) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = self.bind().await?;
+        let core = self.spawn_core();
+        self.run(listener, core, shutdown).await;
+        Ok(())
+    }
+
+    pub(crate) async fn bind(&self) -> std::io::Result<TcpListener> {
         info!(bind_addr = %self.bind_addr, "service started");
 
         let listener = TcpListener::bind(&self.bind_addr).await?;
-        info!(bind_addr = %self.bind_addr, "listening");
+        // с портом 0 реальный адрес известен только после bind
+        let local_addr = listener.local_addr()?;
+        info!(bind_addr = %local_addr, "listening");
+        Ok(listener)
+    }
 
-        let (mq_sndr, mq_rcvr) = mpsc::channel::<Request>(100);
+    pub(crate) fn spawn_core(&self) -> Core {
+        let (tx, rx) = mpsc::channel::<Request>(self.queue_capacity);
 
-        let worker_task = worker::spawn_worker(mq_rcvr, self.data_dir.clone());
+        let stats = Arc::new(Stats::default());
 
-        let mut shutdown_rx = shutdown.clone();
+        let worker = worker::spawn_worker(
+            rx,
+            self.data_dir.clone(),
+            self.settings.clone(),
+            stats.clone(),
+        );
+        let ticker = worker::spawn_ticker(tx.downgrade());
+
+        Core {
+            tx,
+            stats,
+            worker,
+            ticker,
+        }
+    }
 
-        debug_assert!(self.max_connections > 0);
-        debug_assert!(self.max_connections >= 1);
+    /// Прием соединений до сигнала остановки, затем drain и остановка worker.
+    pub(crate) async fn run(&self, listener: TcpListener, core: Core, shutdown: &Shutdown) {
+        let Core {
+            tx: mq_sndr,
+            stats,
+            worker: worker_task,
+            ticker: ticker_task,
+        } = core;
 
-        let stats = Arc::new(Stats::default());
+        let mut shutdown_rx = shutdown.clone();
+
+        let inboxes = Arc::new(Inboxes::default());
 
         let mut client_tasks: Vec<tokio::task::JoinHandle<()>> = Vec::new();
         let mut accept_count: u64 = 0;
@@ -52,13 +105,13 @@ impl Service {
                 _ = shutdown_rx.changed() => break,
                 res = listener.accept() => {
                     if let Ok((socket, peer)) = res {
-                        if client_tasks.len() >= self.max_connections {
+                        if client_tasks.len() >= self.settings.borrow().max_connections {
                             let (mut _r, mut w) = socket.into_split();
-                            ingress::reply(&mut w, stats.as_ref(), Response::ErrBusy).await;
+                            let r = Response::err(ErrorCode::Busy, "too many connections");
+                            ingress::reply(&mut w, stats.as_ref(), r).await;
                             continue;
                         }
-                        debug_assert!(client_tasks.len() <= self.max_connections);
-                        let h = ingress::spawn_client(socket, peer, mq_sndr.clone(), shutdown.clone(), stats.clone());
+                        let h = ingress::spawn_client((socket, peer), mq_sndr.clone(), shutdown.clone(), stats.clone(), inboxes.clone(), self.settings.clone(), self.node_id.clone());
                         client_tasks.push(h);
 
                         accept_count += 1;
@@ -72,21 +125,44 @@ impl Service {
                 }
             }
         }
+        // новых соединений больше не принимаем, текущие дорабатывают начатые запросы
         drop(listener);
-
-        for task in client_tasks {
-            let _ = task.await;
+        client_tasks.retain(|h| !h.is_finished());
+        let drain_timeout_ms = self.settings.borrow().drain_timeout_ms;
+        info!(
+            clients = client_tasks.len(),
+            timeout_ms = drain_timeout_ms,
+            "draining connections"
+        );
+
+        let drain = async {
+            for task in client_tasks.iter_mut() {
+                let _ = task.await;
+            }
+        };
+        if tokio::time::timeout(Duration::from_millis(drain_timeout_ms), drain)
+            .await
+            .is_err()
+        {
+            warn!("drain deadline exceeded, closing remaining connections");
+            for task in &client_tasks {
+                task.abort();
+            }
         }
 
         drop(mq_sndr);
 
+        let _ = ticker_task.await;
         let _ = worker_task.await;
 
         info!("service shutting down");
 
-        let (ack, nack, err_wal, connections) = stats.snapshot();
-        info!(ack, nack, err_wal, connections, "broker stats");
-
-        Ok(())
+        let (ack, nack, err_wal, connections, expired_skips) = stats.snapshot();
+        info!(
+            ack,
+            nack, err_wal, connections, expired_skips, "broker stats"
+        );
+        let (blocked, blocked_ms) = stats.blocked();
+        info!(blocked, blocked_ms, "backpressure stats");
     }
 }
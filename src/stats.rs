@@ -1,4 +1,5 @@
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[derive(Default)]
 pub struct Stats {
@@ -6,7 +7,13 @@ pub struct Stats {
     pub nack: AtomicU64,
     pub err_wal: AtomicU64,
     pub connections: AtomicU64,
+    // просроченные записи, пропущенные чтением; одна запись считается при каждом чтении,
+    // так что это не число истекших сообщений
+    pub expired_skips: AtomicU64,
     pub next_id: AtomicU64,
+    // записи, которые ждали места в очереди к worker, и сколько всего ждали
+    pub blocked: AtomicU64,
+    pub blocked_us: AtomicU64,
 }
 
 impl Stats {
@@ -30,12 +37,31 @@ impl Stats {
         self.connections.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn snapshot(&self) -> (u64, u64, u64, u64) {
+    pub fn add_expired_skips(&self, n: u64) {
+        self.expired_skips.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_blocked(&self, waited: Duration) {
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+        self.blocked_us
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Сколько записей ждали места в очереди и сколько миллисекунд суммарно.
+    pub fn blocked(&self) -> (u64, u64) {
+        (
+            self.blocked.load(Ordering::Relaxed),
+            self.blocked_us.load(Ordering::Relaxed) / 1000,
+        )
+    }
+
+    pub fn snapshot(&self) -> (u64, u64, u64, u64, u64) {
         (
             self.ack.load(Ordering::Relaxed),
             self.nack.load(Ordering::Relaxed),
             self.err_wal.load(Ordering::Relaxed),
             self.connections.load(Ordering::Relaxed),
+            self.expired_skips.load(Ordering::Relaxed),
         )
     }
 }
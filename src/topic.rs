@@ -0,0 +1,346 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, read_dir, rename},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::lease::Leases;
+use crate::protocol::MAX_PRIORITY;
+use crate::wal::{MAX_WAL_BYTES, Wal, WalRecord};
+
+const TOPIC_CONF: &str = "topic.conf";
+
+/// Настройки топика, хранятся в `<data_dir>/<topic>/topic.conf` строками `key=value`.
+#[derive(Default, Clone)]
+pub struct TopicConfig {
+    pub ttl_ms: Option<u64>,
+    // после стольких выдач без ACKMSG запись уходит в dead-letter топик
+    pub max_attempts: Option<u32>,
+    pub dlq: Option<String>,
+}
+
+impl TopicConfig {
+    pub fn load(topic_dir: &Path) -> std::io::Result<Self> {
+        let path = topic_dir.join(TOPIC_CONF);
+        let mut conf = TopicConfig::default();
+        if !path.exists() {
+            return Ok(conf);
+        }
+
+        let f = File::open(&path)?;
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let Some((key, val)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "ttl_ms" => conf.ttl_ms = val.trim().parse::<u64>().ok(),
+                "max_attempts" => conf.max_attempts = val.trim().parse::<u32>().ok(),
+                "dlq" => conf.dlq = Some(val.trim().to_string()).filter(|v| !v.is_empty()),
+                other => tracing::warn!(key = %other, "unknown topic config key"),
+            }
+        }
+        Ok(conf)
+    }
+
+    pub fn save(&self, topic_dir: &Path) -> std::io::Result<()> {
+        let path = topic_dir.join(TOPIC_CONF);
+        let tmp = topic_dir.join(format!("{}.tmp", TOPIC_CONF));
+        {
+            let mut f = File::create(&tmp)?;
+            if let Some(ttl) = self.ttl_ms {
+                writeln!(f, "ttl_ms={}", ttl)?;
+            }
+            if let Some(n) = self.max_attempts {
+                writeln!(f, "max_attempts={}", n)?;
+            }
+            if let Some(dlq) = &self.dlq {
+                writeln!(f, "dlq={}", dlq)?;
+            }
+            f.sync_all()?;
+        }
+        rename(tmp, path)
+    }
+}
+
+/// Настройки топиков из конфига брокера: действуют, пока в topic.conf не задано свое.
+#[derive(Clone)]
+pub struct TopicDefaults {
+    pub ttl_ms: Option<u64>,
+    pub max_attempts: Option<u32>,
+    // емкость memory-топика, если CREATE не указал CAPACITY
+    pub mem_capacity: usize,
+    pub max_wal_bytes: u64,
+}
+
+impl Default for TopicDefaults {
+    fn default() -> Self {
+        TopicDefaults {
+            ttl_ms: None,
+            max_attempts: None,
+            mem_capacity: 10_000,
+            max_wal_bytes: MAX_WAL_BYTES,
+        }
+    }
+}
+
+pub struct DeadLetter {
+    pub prio: u8,
+    pub record: WalRecord,
+    pub attempts: u32,
+    pub reason: String,
+}
+
+/// Уровень приоритета топика: свой WAL и свое состояние очереди.
+/// Уровень 0 живет прямо в каталоге топика, уровни 1..=9 - в подкаталогах `p<N>`.
+struct Level {
+    dir: PathBuf,
+    wal: Wal,
+    // состояние очереди открывается при первом RECEIVE
+    leases: Option<Leases>,
+}
+
+impl Level {
+    fn open(dir: PathBuf, max_wal_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut wal = Wal::open(dir.join("wal.log"))?;
+        wal.set_max_bytes(max_wal_bytes);
+        Ok(Level {
+            dir,
+            wal,
+            leases: None,
+        })
+    }
+}
+
+pub struct Topic {
+    pub dir: PathBuf,
+    pub conf: TopicConfig,
+    defaults: TopicDefaults,
+    levels: BTreeMap<u8, Level>,
+}
+
+impl Topic {
+    pub fn open(data_dir: &str, name: &str, defaults: &TopicDefaults) -> std::io::Result<Self> {
+        let dir = Path::new(data_dir).join(name);
+        std::fs::create_dir_all(&dir)?;
+        let conf = TopicConfig::load(&dir)?;
+
+        let mut levels = BTreeMap::new();
+        levels.insert(0, Level::open(dir.clone(), defaults.max_wal_bytes)?);
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            if let Some(prio) = level_of(&path) {
+                levels.insert(prio, Level::open(path, defaults.max_wal_bytes)?);
+            }
+        }
+
+        Ok(Topic {
+            dir,
+            conf,
+            defaults: defaults.clone(),
+            levels,
+        })
+    }
+
+    /// Новые настройки по умолчанию после перечитывания конфига.
+    pub fn set_defaults(&mut self, defaults: &TopicDefaults) {
+        for level in self.levels.values_mut() {
+            level.wal.set_max_bytes(defaults.max_wal_bytes);
+        }
+        self.defaults = defaults.clone();
+    }
+
+    pub fn exists(data_dir: &str, name: &str) -> bool {
+        Path::new(data_dir).join(name).join("wal.log").exists()
+    }
+
+    /// WAL уровня `prio`, создается при первой записи.
+    pub fn wal_mut(&mut self, prio: u8) -> std::io::Result<&mut Wal> {
+        if !self.levels.contains_key(&prio) {
+            let level = Level::open(
+                self.dir.join(format!("p{}", prio)),
+                self.defaults.max_wal_bytes,
+            )?;
+            self.levels.insert(prio, level);
+        }
+        Ok(&mut self.levels.get_mut(&prio).expect("level opened above").wal)
+    }
+
+    /// WAL уровня `prio`, если в него уже писали.
+    pub fn wal(&self, prio: u8) -> Option<&Wal> {
+        self.levels.get(&prio).map(|l| &l.wal)
+    }
+
+    pub fn wals(&self) -> impl DoubleEndedIterator<Item = (u8, &Wal)> {
+        self.levels.iter().map(|(p, l)| (*p, &l.wal))
+    }
+
+    pub fn wals_mut(&mut self) -> impl Iterator<Item = (u8, &mut Wal)> {
+        self.levels.iter_mut().map(|(p, l)| (*p, &mut l.wal))
+    }
+
+    /// Выдает в аренду следующую запись очереди. Уровни обходятся от старшего к младшему,
+    /// внутри уровня сначала записи с истекшей арендой, затем еще не выдававшиеся.
+    /// Возвращает уровень, запись и номер попытки.
+    pub fn receive(
+        &mut self,
+        now_ms: u64,
+        visibility_ms: u64,
+    ) -> std::io::Result<Option<(u8, WalRecord, u32)>> {
+        let max_attempts = self.max_attempts();
+
+        for (prio, level) in self.levels.iter_mut().rev() {
+            let leases = leases_mut(&mut level.leases, &level.dir)?;
+
+            while let Some(offset) = leases.next_redelivery(now_ms, max_attempts) {
+                match level.wal.read_at(offset, now_ms)? {
+                    Some(r) => {
+                        let attempts = leases.lease(offset, now_ms, visibility_ms)?;
+                        return Ok(Some((*prio, r, attempts)));
+                    }
+                    // запись просрочена или удалена retention-ом - выдавать нечего
+                    _ => {
+                        leases.settle(offset, true, None)?;
+                    }
+                }
+            }
+
+            let (records, _) = level
+                .wal
+                .read_from(leases.cursor(), 1, usize::MAX, now_ms)?;
+            if let Some(r) = records.into_iter().next() {
+                let attempts = leases.lease(r.offset, now_ms, visibility_ms)?;
+                return Ok(Some((*prio, r, attempts)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// ACKMSG/NACKMSG. `false` - записи с такой арендой нет.
+    pub fn settle(
+        &mut self,
+        prio: u8,
+        offset: u64,
+        ack: bool,
+        reason: Option<&str>,
+    ) -> std::io::Result<bool> {
+        match self.levels.get_mut(&prio) {
+            Some(level) => leases_mut(&mut level.leases, &level.dir)?.settle(offset, ack, reason),
+            None => Ok(false),
+        }
+    }
+
+    /// Dead-letter топик: явно заданный или `<topic>.dlq`.
+    pub fn dlq_name(&self, name: &str) -> String {
+        self.conf
+            .dlq
+            .clone()
+            .unwrap_or_else(|| format!("{}.dlq", name))
+    }
+
+    /// Записи, исчерпавшие `max_attempts`. Аренда с них не снимается:
+    /// вызывающий сначала пишет их в dead-letter топик, потом делает `settle`.
+    pub fn dead_letters(&mut self, now_ms: u64) -> std::io::Result<Vec<DeadLetter>> {
+        let Some(max_attempts) = self.max_attempts() else {
+            return Ok(Vec::new());
+        };
+
+        let mut out = Vec::new();
+        for (prio, level) in self.levels.iter_mut() {
+            let leases = leases_mut(&mut level.leases, &level.dir)?;
+
+            for (offset, attempts, reason) in leases.exhausted(now_ms, max_attempts) {
+                match level.wal.read_at(offset, now_ms)? {
+                    Some(record) => out.push(DeadLetter {
+                        prio: *prio,
+                        record,
+                        attempts,
+                        reason: reason.unwrap_or_else(|| "visibility timeout".to_string()),
+                    }),
+                    // запись уже недоступна - переносить нечего
+                    _ => {
+                        leases.settle(offset, true, None)?;
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Срок жизни записи: TTL сообщения важнее TTL топика.
+    pub fn expires_at(&self, now_ms: u64, msg_ttl_ms: Option<u64>) -> Option<u64> {
+        msg_ttl_ms
+            .or(self.conf.ttl_ms)
+            .or(self.defaults.ttl_ms)
+            .map(|ttl| now_ms.saturating_add(ttl))
+    }
+
+    fn max_attempts(&self) -> Option<u32> {
+        self.conf.max_attempts.or(self.defaults.max_attempts)
+    }
+}
+
+/// Каталоги уровней топика по возрастанию приоритета. WAL при этом не открываются.
+pub fn level_dirs(topic_dir: &Path) -> std::io::Result<Vec<(u8, PathBuf)>> {
+    let mut out = vec![(0, topic_dir.to_path_buf())];
+    for entry in read_dir(topic_dir)? {
+        let path = entry?.path();
+        if let Some(prio) = level_of(&path) {
+            out.push((prio, path));
+        }
+    }
+    out.sort_by_key(|(prio, _)| *prio);
+    Ok(out)
+}
+
+// `p<N>` -> N, если N - допустимый ненулевой уровень приоритета
+fn level_of(path: &Path) -> Option<u8> {
+    if !path.is_dir() {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    let prio = name.strip_prefix('p')?.parse::<u8>().ok()?;
+    (1..=MAX_PRIORITY).contains(&prio).then_some(prio)
+}
+
+fn leases_mut<'a>(slot: &'a mut Option<Leases>, dir: &Path) -> std::io::Result<&'a mut Leases> {
+    if slot.is_none() {
+        *slot = Some(Leases::open(dir)?);
+    }
+    Ok(slot.as_mut().expect("leases opened above"))
+}
+
+/// Имена всех топиков в `data_dir`. Служебные каталоги (`__*`) не входят.
+pub fn list_topics(data_dir: &str) -> std::io::Result<Vec<String>> {
+    let mut out = Vec::new();
+    for entry in read_dir(data_dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if name.starts_with("__") || !path.join("wal.log").is_file() {
+            continue;
+        }
+        out.push(name.to_string());
+    }
+    out.sort();
+    Ok(out)
+}
+
+/// Сопоставление имени топика с шаблоном подписки. Токены разделяются точкой:
+/// `*` - ровно один токен, `>` - один и более токенов до конца имени.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let mut names = name.split('.');
+    for p in pattern.split('.') {
+        match (p, names.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (p, Some(n)) if p == n => {}
+            _ => return false,
+        }
+    }
+    names.next().is_none()
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::clock;
+
+/// Незавершенная транзакция, найденная при старте: ее записи нужно откатить.
+pub struct PendingTx {
+    pub txid: u64,
+    pub participants: Vec<(String, u8)>,
+}
+
+/// Журнал транзакций `__tx/tx.log`. Хранит только транзакцию, которая сейчас пишется:
+///   `P\t<txid>\t<prio>\t<topic>` - участник (WAL, куда пойдут записи)
+///   `C\t<txid>` - commit-маркер, после fsync транзакция считается зафиксированной
+///   `A\t<txid>` - транзакция откачена
+/// После C/A журнал обнуляется.
+pub struct TxLog {
+    file: File,
+    next_txid: u64,
+}
+
+impl TxLog {
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<(Self, Vec<PendingTx>)> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join("tx.log");
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let mut open: BTreeMap<u64, Vec<(String, u8)>> = BTreeMap::new();
+        let mut max_txid: u64 = 0;
+
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let Ok(line) = line else { break };
+            let mut it = line.split('\t');
+            let kind = it.next();
+            let Some(txid) = it.next().and_then(|v| v.parse::<u64>().ok()) else {
+                break;
+            };
+            max_txid = max_txid.max(txid);
+
+            match kind {
+                Some("P") => {
+                    let prio = it.next().and_then(|v| v.parse::<u8>().ok());
+                    let topic = it.next().filter(|t| !t.is_empty());
+                    let (Some(prio), Some(topic)) = (prio, topic) else {
+                        break;
+                    };
+                    open.entry(txid)
+                        .or_default()
+                        .push((topic.to_string(), prio));
+                }
+                Some("C") | Some("A") => {
+                    open.remove(&txid);
+                }
+                _ => break,
+            }
+        }
+
+        let pending = open
+            .into_iter()
+            .map(|(txid, participants)| PendingTx { txid, participants })
+            .collect();
+
+        let log = TxLog {
+            file,
+            // id должны расти и между рестартами
+            next_txid: (max_txid + 1).max(clock::now_ms()),
+        };
+        Ok((log, pending))
+    }
+
+    /// Регистрирует участников новой транзакции, возвращает ее id.
+    pub fn begin(&mut self, participants: &[(String, u8)]) -> std::io::Result<u64> {
+        let txid = self.next_txid;
+        self.next_txid += 1;
+
+        let mut buf = String::new();
+        for (topic, prio) in participants {
+            buf.push_str(&format!("P\t{}\t{}\t{}\n", txid, prio, topic));
+        }
+        self.file.write_all(buf.as_bytes())?;
+        self.file.sync_all()?;
+        Ok(txid)
+    }
+
+    pub fn commit(&mut self, txid: u64) -> std::io::Result<()> {
+        self.finish("C", txid)
+    }
+
+    pub fn abort(&mut self, txid: u64) -> std::io::Result<()> {
+        self.finish("A", txid)
+    }
+
+    fn finish(&mut self, marker: &str, txid: u64) -> std::io::Result<()> {
+        writeln!(self.file, "{}\t{}", marker, txid)?;
+        self.file.sync_all()?;
+
+        // маркер уже на диске - журнал можно обнулить
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
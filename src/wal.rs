@@ -5,14 +5,47 @@ use std::{
     path::{Path, PathBuf},
 };
 
-const MAX_WAL_BYTES: u64 = 16 * 1024 * 1024; // 16MB
+// размер сегмента по умолчанию, после которого wal.log ротируется
+pub const MAX_WAL_BYTES: u64 = 16 * 1024 * 1024; // 16MB
+
+/// Запись как она лежит в файле, для `wal inspect`.
+pub struct RawRecord {
+    pub offset: u64,
+    pub id: u64,
+    pub payload: String,
+    pub meta: String,
+}
+
+/// Результат проверки одного файла WAL.
+pub struct SegmentCheck {
+    pub path: PathBuf,
+    pub start: u64,
+    // offset после последней целой записи
+    pub next: u64,
+    pub error: Option<std::io::Error>,
+}
 
 pub struct WalRecord {
     pub offset: u64,
     pub id: u64,
     pub payload: String,
+    pub headers: Vec<(String, String)>,
 }
 
+/// Оценка размера записи в ответе: payload, заголовки и запас на offset, id и разделители.
+/// Не меньше строки FETCH, так что бюджет чтения не отрежет то, что влезло бы в ответ.
+pub fn record_bytes(payload: &str, headers: &[(String, String)]) -> usize {
+    // `key=base64(value),`
+    let headers: usize = headers
+        .iter()
+        .map(|(k, v)| k.len() + v.len().div_ceil(3) * 4 + 2)
+        .sum();
+    payload.len() + headers + 48
+}
+
+// пользовательские заголовки лежат в метаданных как `h.<key>=<base64 value>`
+const HEADER_PREFIX: &str = "h.";
+
 // struct WalEntry {
 //     offset: u64,
 //     id: u64,
@@ -55,6 +88,9 @@ pub struct Wal {
     data_dir: PathBuf,
     next_offset: u64,
     segment_start_offset: u64,
+    // первый offset, еще не удаленный retention-ом
+    start_offset: u64,
+    max_bytes: u64,
 }
 
 impl Wal {
@@ -77,32 +113,177 @@ impl Wal {
             data_dir,
             next_offset: 0,
             segment_start_offset: 0,
+            start_offset: 0,
+            max_bytes: MAX_WAL_BYTES,
         };
 
         wal.recover_all()?;
         Ok(wal)
     }
 
-    pub fn append_msg(&mut self, id: u64, msg: &str) -> std::io::Result<u64> {
-        use base64::{Engine as _, engine::general_purpose::STANDARD};
+    /// Offset, который получит следующая запись (high watermark).
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// Размер сегмента, после которого wal.log ротируется.
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+    }
+
+    /// Начало лога: записи до него удалены retention-ом.
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    /// Финальный fsync перед остановкой.
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// `ts_ms` - время записи, по нему SEEK ищет offset.
+    pub fn append_msg(
+        &mut self,
+        id: u64,
+        msg: &str,
+        ts_ms: u64,
+        expires_at: Option<u64>,
+    ) -> std::io::Result<u64> {
+        self.append_with_headers(id, msg, ts_ms, expires_at, &[])
+    }
+
+    pub fn append_with_headers(
+        &mut self,
+        id: u64,
+        msg: &str,
+        ts_ms: u64,
+        expires_at: Option<u64>,
+        headers: &[(&str, &str)],
+    ) -> std::io::Result<u64> {
         let payload_b64 = STANDARD.encode(msg.as_bytes());
-        self.append_b64(id, &payload_b64)
+        let mut meta = format_meta(&[("ts", Some(ts_ms)), ("exp", expires_at)]);
+        for (k, v) in headers {
+            if !meta.is_empty() {
+                meta.push(',');
+            }
+            meta.push_str(&format!(
+                "{}{}={}",
+                HEADER_PREFIX,
+                k,
+                STANDARD.encode(v.as_bytes())
+            ));
+        }
+        self.append_b64(id, &payload_b64, &meta)
     }
 
-    fn append_b64(&mut self, id: u64, payload_b64: &str) -> std::io::Result<u64> {
+    fn append_b64(&mut self, id: u64, payload_b64: &str, meta: &str) -> std::io::Result<u64> {
         self.rotate_if_needed()?;
 
         let offset = self.next_offset;
-        writeln!(self.file, "{}\t{}\t{}", offset, id, payload_b64)?;
+        writeln!(self.file, "{}", format_line(offset, id, payload_b64, meta))?;
         self.file.sync_all()?;
 
         self.next_offset += 1;
         Ok(offset)
     }
 
+    /// Пишет пачку записей одним write + одним fsync.
+    /// Первая запись несет маркер `batch=<last>`, чтобы recovery мог
+    /// отбросить недописанную пачку целиком.
+    /// `txid` помечает записи транзакции - по нему `rollback_tx` найдет их в хвосте.
+    pub fn append_batch(
+        &mut self,
+        records: &[(u64, String)],
+        ts_ms: u64,
+        expires_at: Option<u64>,
+        txid: Option<u64>,
+    ) -> std::io::Result<(u64, u64)> {
+        if records.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "empty batch",
+            ));
+        }
+
+        self.rotate_if_needed()?;
+
+        let first = self.next_offset;
+        let last = first + records.len() as u64 - 1;
+
+        let mut buf = String::new();
+        for (i, (id, msg)) in records.iter().enumerate() {
+            let offset = first + i as u64;
+            let payload_b64 = STANDARD.encode(msg.as_bytes());
+            let batch = (i == 0 && first != last).then_some(last);
+            let meta = format_meta(&[
+                ("batch", batch),
+                ("ts", Some(ts_ms)),
+                ("exp", expires_at),
+                ("tx", txid),
+            ]);
+            buf.push_str(&format_line(offset, *id, &payload_b64, &meta));
+            buf.push('\n');
+        }
+
+        let start_len = self.file.metadata()?.len();
+        let res = self
+            .file
+            .write_all(buf.as_bytes())
+            .and_then(|_| self.file.sync_all());
+
+        if let Err(e) = res {
+            // откатываем хвост, чтобы полпачки не стало видно
+            self.file.set_len(start_len)?;
+            self.file.seek(SeekFrom::End(0))?;
+            return Err(e);
+        }
+
+        self.next_offset = last + 1;
+        Ok((first, last))
+    }
+
+    /// Отрезает с хвоста текущего сегмента записи транзакции `txid`.
+    /// Пачка транзакции всегда пишется в конец текущего сегмента, и до commit-маркера
+    /// после нее ничего не пишется, поэтому искать ее достаточно в хвосте wal.log.
+    /// Возвращает число удаленных записей.
+    pub fn rollback_tx(&mut self, txid: u64) -> std::io::Result<u64> {
+        let f = OpenOptions::new().read(true).open(&self.wal_path)?;
+
+        let mut pos: u64 = 0;
+        // (позиция и offset начала текущей серии записей txid)
+        let mut run: Option<(u64, u64)> = None;
+
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let Some((off, _id, _payload, meta)) = parse_record(&line) else {
+                break;
+            };
+
+            let is_tx = meta_get(meta, "tx").and_then(|v| v.parse::<u64>().ok()) == Some(txid);
+            match (is_tx, run) {
+                (true, None) => run = Some((pos, off)),
+                (false, _) => run = None,
+                _ => {}
+            }
+            pos += (line.len() + 1) as u64;
+        }
+
+        let Some((run_pos, run_offset)) = run else {
+            return Ok(0);
+        };
+
+        self.file.set_len(run_pos)?;
+        self.file.sync_all()?;
+        self.file.seek(SeekFrom::End(0))?;
+
+        let removed = self.next_offset - run_offset;
+        self.next_offset = run_offset;
+        Ok(removed)
+    }
+
     fn rotate_if_needed(&mut self) -> std::io::Result<()> {
         let size = self.file.metadata()?.len();
-        if size < MAX_WAL_BYTES {
+        if size < self.max_bytes {
             return Ok(());
         }
 
@@ -127,10 +308,79 @@ impl Wal {
         Ok(())
     }
 
+    /// Те же проверки, что при открытии WAL, но без обрезки хвоста: файлы только читаются.
+    pub fn verify(dir: &Path) -> std::io::Result<Vec<SegmentCheck>> {
+        let files = list_wal_files(dir)?;
+        let mut expected: u64 = match files.first() {
+            Some((n, _)) if *n != u64::MAX => *n,
+            Some((_, path)) => Self::first_offset(path)?.unwrap_or(0),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut out = Vec::new();
+        for (n, path) in files {
+            let start = if n == u64::MAX { expected } else { n };
+            let mut error = (start != expected).then(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("wal segment starts at {}, expected {}", start, expected),
+                )
+            });
+            let next = match Self::recover_file(&path, start, false) {
+                Ok((next, _)) => next,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    // целая часть файла - то, что осталось бы после обрезки при открытии
+                    Self::recover_file(&path, start, true)?.0
+                }
+            };
+            out.push(SegmentCheck {
+                path,
+                start,
+                next,
+                error,
+            });
+            expected = next;
+        }
+        Ok(out)
+    }
+
+    /// Файлы WAL каталога в порядке offset-ов, wal.log последним.
+    pub fn segment_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        Ok(list_wal_files(dir)?.into_iter().map(|(_, p)| p).collect())
+    }
+
+    /// Записи файла до первой нечитаемой строки; вторым значением - номер этой строки (с 1).
+    pub fn read_raw(path: &Path) -> std::io::Result<(Vec<RawRecord>, Option<usize>)> {
+        let f = OpenOptions::new().read(true).open(path)?;
+        let mut out = Vec::new();
+        for (i, line) in BufReader::new(f).lines().enumerate() {
+            let Ok(line) = line else {
+                return Ok((out, Some(i + 1)));
+            };
+            let Some((offset, id, payload, meta)) = parse_record(&line) else {
+                return Ok((out, Some(i + 1)));
+            };
+            let bytes = STANDARD.decode(payload).unwrap_or_default();
+            out.push(RawRecord {
+                offset,
+                id,
+                payload: String::from_utf8_lossy(&bytes).into_owned(),
+                meta: meta.to_string(),
+            });
+        }
+        Ok((out, None))
+    }
+
     fn recover_all(&mut self) -> std::io::Result<()> {
         let files = list_wal_files(&self.data_dir)?;
 
-        let mut expected: u64 = 0;
+        // голова лога могла быть удалена retention-ом
+        let mut expected: u64 = match files.first() {
+            Some((n, _)) if *n != u64::MAX => *n,
+            _ => Self::first_offset(&self.wal_path)?.unwrap_or(0),
+        };
+        self.start_offset = expected;
 
         for (start, path) in files.iter().filter(|(n, _)| *n != u64::MAX).cloned() {
             if start != expected {
@@ -154,6 +404,13 @@ impl Wal {
         Ok(())
     }
 
+    fn first_offset(path: &Path) -> std::io::Result<Option<u64>> {
+        let f = OpenOptions::new().read(true).open(path)?;
+        let mut line = String::new();
+        BufReader::new(f).read_line(&mut line)?;
+        Ok(parse_record(line.trim_end_matches('\n')).map(|(off, _, _, _)| off))
+    }
+
     fn recover_file(
         path: &Path,
         mut expected: u64,
@@ -165,6 +422,8 @@ impl Wal {
         let reader = BufReader::new(&f);
         let mut valid_end_pos: u64 = 0;
         let mut pos: u64 = 0;
+        // (last offset, expected и позиция до начала пачки)
+        let mut pending_batch: Option<(u64, u64, u64)> = None;
 
         for line in reader.lines() {
             let line = match line {
@@ -175,20 +434,20 @@ impl Wal {
                     }
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
-                        "wal read error",
+                        format!("wal read error at offset {}", expected),
                     ));
                 }
             };
 
             let line_len = (line.len() + 1) as u64;
 
-            let Some((off, _id, _payload)) = parse_record(&line) else {
+            let Some((off, _id, _payload, meta)) = parse_record(&line) else {
                 if allow_tail_truncate {
                     break;
                 }
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
-                    "wal record parse error",
+                    format!("wal record parse error at offset {}", expected),
                 ));
             };
 
@@ -198,34 +457,85 @@ impl Wal {
                 }
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
-                    "wal offset mismatch",
+                    format!("wal offset mismatch: got {}, expected {}", off, expected),
                 ));
             }
 
+            if pending_batch.is_none()
+                && let Some(last) = meta_get(meta, "batch").and_then(|v| v.parse::<u64>().ok())
+            {
+                pending_batch = Some((last, expected, valid_end_pos));
+            }
+
             expected += 1;
             pos += line_len;
             valid_end_pos = pos;
+
+            if let Some((last, _, _)) = pending_batch
+                && off >= last
+            {
+                pending_batch = None;
+            }
+        }
+
+        if let Some((_, batch_start, batch_pos)) = pending_batch {
+            if !allow_tail_truncate {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("wal batch incomplete at offset {}", batch_start),
+                ));
+            }
+            // пачка дописана не полностью - отбрасываем ее целиком
+            return Ok((batch_start, batch_pos));
         }
 
         Ok((expected, valid_end_pos))
     }
 
-    pub fn read_from(&self, from: u64, limit: usize) -> std::io::Result<Vec<WalRecord>> {
-        if limit == 0 {
-            return Ok(Vec::new());
+    /// Первый offset с временем записи `>= ts_ms`, или high watermark, если таких нет.
+    /// Записи без метки времени (старый формат) считаются более ранними.
+    pub fn offset_for_time(&self, ts_ms: u64) -> std::io::Result<u64> {
+        for (_n, path) in list_wal_files(&self.data_dir)? {
+            let f = OpenOptions::new().read(true).open(&path)?;
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                let Some((off, _id, _payload, meta)) = parse_record(&line) else {
+                    continue;
+                };
+                let ts = meta_get(meta, "ts").and_then(|v| v.parse::<u64>().ok());
+                if ts.is_some_and(|ts| ts >= ts_ms) {
+                    return Ok(off);
+                }
+            }
         }
+        Ok(self.next_offset)
+    }
 
-        let files = list_wal_files(&self.data_dir)?;
+    /// Читает до `limit` записей и около `max_bytes` payload-а начиная с `from`,
+    /// пропуская просроченные.
+    /// Вторым значением возвращает число пропущенных просроченных записей.
+    pub fn read_from(
+        &self,
+        from: u64,
+        limit: usize,
+        max_bytes: usize,
+        now_ms: u64,
+    ) -> std::io::Result<(Vec<WalRecord>, u64)> {
+        if limit == 0 {
+            return Ok((Vec::new(), 0));
+        }
 
-        let mut out = Vec::with_capacity(limit);
+        let mut out = Vec::new();
+        let mut expired: u64 = 0;
+        let mut read_bytes: usize = 0;
 
-        for (_n, path) in files {
+        for path in self.files_from(from)? {
             let f = OpenOptions::new().read(true).open(&path)?;
             let reader = BufReader::new(f);
 
             for line in reader.lines() {
                 let line = line?;
-                let Some((off, id, payload)) = parse_record(&line) else {
+                let Some((off, id, payload, meta)) = parse_record(&line) else {
                     continue;
                 };
 
@@ -233,49 +543,282 @@ impl Wal {
                     continue;
                 }
 
-                let bytes = STANDARD.decode(payload.as_bytes()).map_err(|_| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "wal payload base64 decode failed",
-                    )
-                })?;
+                if is_expired(meta, now_ms) {
+                    expired += 1;
+                    continue;
+                }
 
-                let payload = String::from_utf8(bytes).map_err(|_| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "wal payload utf8 decode failed",
-                    )
-                })?;
-
-                out.push(WalRecord {
-                    offset: off,
-                    id,
-                    payload: payload.to_string(),
-                });
-
-                if out.len() >= limit {
-                    return Ok(out);
+                let r = decode_record(off, id, payload, meta)?;
+                read_bytes += record_bytes(&r.payload, &r.headers);
+                out.push(r);
+
+                // первая запись отдается всегда, дальше - пока не набран бюджет ответа
+                if out.len() >= limit || read_bytes >= max_bytes {
+                    return Ok((out, expired));
                 }
             }
         }
 
-        Ok(out)
+        Ok((out, expired))
+    }
+
+    /// Одна запись по offset-у; `None`, если она просрочена или уже удалена.
+    /// Читает только сегмент с этим offset-ом и останавливается на нем.
+    pub fn read_at(&self, offset: u64, now_ms: u64) -> std::io::Result<Option<WalRecord>> {
+        for path in self.files_from(offset)? {
+            let f = OpenOptions::new().read(true).open(&path)?;
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                let Some((off, id, payload, meta)) = parse_record(&line) else {
+                    continue;
+                };
+                if off < offset {
+                    continue;
+                }
+                if off > offset || is_expired(meta, now_ms) {
+                    return Ok(None);
+                }
+                return decode_record(off, id, payload, meta).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Файлы лога, в которых могут быть записи с offset-ом `>= from`:
+    /// сегменты, целиком лежащие ниже `from`, не читаются.
+    fn files_from(&self, from: u64) -> std::io::Result<Vec<PathBuf>> {
+        let files = list_wal_files(&self.data_dir)?;
+        // начало wal.log известно только из состояния, в имени его нет
+        let starts: Vec<u64> = files
+            .iter()
+            .map(|(n, _)| {
+                if *n == u64::MAX {
+                    self.segment_start_offset
+                } else {
+                    *n
+                }
+            })
+            .collect();
+
+        Ok(files
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| starts.get(i + 1).is_none_or(|next| *next > from))
+            .map(|(_, (_, path))| path)
+            .collect())
+    }
+
+    /// Удаляет с головы лога закрытые сегменты, в которых все записи просрочены.
+    /// Возвращает число удаленных сегментов.
+    pub fn delete_expired_segments(&mut self, now_ms: u64) -> std::io::Result<usize> {
+        let files = list_wal_files(&self.data_dir)?;
+        let sealed: Vec<(u64, PathBuf)> =
+            files.into_iter().filter(|(n, _)| *n != u64::MAX).collect();
+
+        let mut deleted = 0;
+        for (i, (_, path)) in sealed.iter().enumerate() {
+            // пустой wal.log без закрытых сегментов не даст восстановить начало лога
+            let is_last_sealed = i + 1 == sealed.len();
+            if is_last_sealed && self.next_offset == self.segment_start_offset {
+                break;
+            }
+
+            if !Self::segment_fully_expired(path, now_ms)? {
+                break;
+            }
+
+            std::fs::remove_file(path)?;
+            deleted += 1;
+
+            // лог теперь начинается со следующего сегмента
+            self.start_offset = sealed
+                .get(i + 1)
+                .map_or(self.segment_start_offset, |(n, _)| *n);
+        }
+
+        Ok(deleted)
+    }
+
+    fn segment_fully_expired(path: &Path, now_ms: u64) -> std::io::Result<bool> {
+        let f = OpenOptions::new().read(true).open(path)?;
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let Some((_off, _id, _payload, meta)) = parse_record(&line) else {
+                return Ok(false);
+            };
+            if !is_expired(meta, now_ms) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 }
 
 // ВАЖНО: у тебя эта функция уже есть и валидирует base64 - оставь свою.
 // Главное: вернуть Some(off, id, payload) только если payload base64 валиден.
-fn parse_record(line: &str) -> Option<(u64, u64, &str)> {
+// Необязательное 4-е поле - метаданные записи вида `key=value,key=value`.
+fn parse_record(line: &str) -> Option<(u64, u64, &str, &str)> {
     use base64::{Engine as _, engine::general_purpose::STANDARD};
 
     let mut it = line.split('\t');
     let off = it.next()?.parse::<u64>().ok()?;
     let id = it.next()?.parse::<u64>().ok()?;
     let payload = it.next()?;
+    let meta = it.next().unwrap_or("");
     if it.next().is_some() {
         return None;
     }
 
     STANDARD.decode(payload).ok()?;
-    Some((off, id, payload))
+    Some((off, id, payload, meta))
+}
+
+fn format_line(offset: u64, id: u64, payload_b64: &str, meta: &str) -> String {
+    if meta.is_empty() {
+        format!("{}\t{}\t{}", offset, id, payload_b64)
+    } else {
+        format!("{}\t{}\t{}\t{}", offset, id, payload_b64, meta)
+    }
+}
+
+fn format_meta(pairs: &[(&str, Option<u64>)]) -> String {
+    pairs
+        .iter()
+        .filter_map(|(k, v)| v.map(|v| format!("{}={}", k, v)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode_record(offset: u64, id: u64, payload: &str, meta: &str) -> std::io::Result<WalRecord> {
+    let bytes = STANDARD.decode(payload.as_bytes()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "wal payload base64 decode failed",
+        )
+    })?;
+
+    let payload = String::from_utf8(bytes).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "wal payload utf8 decode failed",
+        )
+    })?;
+
+    Ok(WalRecord {
+        offset,
+        id,
+        payload,
+        headers: parse_headers(meta),
+    })
+}
+
+fn is_expired(meta: &str, now_ms: u64) -> bool {
+    meta_get(meta, "exp")
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|exp| exp <= now_ms)
+}
+
+fn parse_headers(meta: &str) -> Vec<(String, String)> {
+    meta.split(',')
+        .filter_map(|kv| kv.split_once('='))
+        .filter_map(|(k, v)| {
+            let key = k.strip_prefix(HEADER_PREFIX)?;
+            let value = String::from_utf8(STANDARD.decode(v).ok()?).ok()?;
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn meta_get<'a>(meta: &'a str, key: &str) -> Option<&'a str> {
+    meta.split(',')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(msgs: &[&str]) -> Vec<(u64, String)> {
+        msgs.iter()
+            .enumerate()
+            .map(|(i, m)| (i as u64, m.to_string()))
+            .collect()
+    }
+
+    fn payloads(wal: &Wal) -> Vec<String> {
+        let (records, _) = wal.read_from(0, 100, usize::MAX, 0).unwrap();
+        records.into_iter().map(|r| r.payload).collect()
+    }
+
+    #[test]
+    fn half_written_batch_is_dropped_on_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append_msg(0, "a", 0, None).unwrap();
+        wal.append_batch(&batch(&["b1", "b2", "b3"]), 0, None, None)
+            .unwrap();
+        drop(wal);
+
+        // обрываем файл посреди второй записи пачки
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        let cut = lines[0].len() + 1 + lines[1].len() + 1 + lines[2].len() / 2;
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.set_len(cut as u64).unwrap();
+
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(wal.next_offset(), 1);
+        assert_eq!(payloads(&wal), ["a"]);
+        // хвост обрезан - новые записи встают на место пачки
+        assert_eq!(wal.append_msg(1, "c", 0, None).unwrap(), 1);
+        drop(wal);
+        assert_eq!(payloads(&Wal::open(&path).unwrap()), ["a", "c"]);
+    }
+
+    #[test]
+    fn rollback_tx_removes_only_its_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append_batch(&batch(&["t1", "t2"]), 0, None, Some(7))
+            .unwrap();
+        wal.append_msg(2, "a", 0, None).unwrap();
+        wal.append_batch(&batch(&["u1", "u2"]), 0, None, Some(8))
+            .unwrap();
+
+        // пачка tx 7 уже не в хвосте - ее не трогаем
+        assert_eq!(wal.rollback_tx(7).unwrap(), 0);
+        assert_eq!(wal.rollback_tx(8).unwrap(), 2);
+        assert_eq!(wal.next_offset(), 3);
+        assert_eq!(wal.append_msg(3, "b", 0, None).unwrap(), 3);
+        drop(wal);
+
+        let wal = Wal::open(&path).unwrap();
+        assert_eq!(payloads(&wal), ["t1", "t2", "a", "b"]);
+    }
+
+    #[test]
+    fn read_at_finds_record_in_any_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = Wal::open(dir.path().join("wal.log")).unwrap();
+        // каждая запись уходит в свой сегмент
+        wal.set_max_bytes(1);
+        wal.append_msg(1, "a", 0, None).unwrap();
+        wal.append_msg(2, "b", 0, Some(100)).unwrap();
+        wal.append_msg(3, "c", 0, None).unwrap();
+
+        let read = |off| wal.read_at(off, 200).unwrap().map(|r| r.payload);
+        assert_eq!(read(0).as_deref(), Some("a"));
+        // просроченная запись не подменяется следующей
+        assert_eq!(read(1), None);
+        assert_eq!(read(2).as_deref(), Some("c"));
+        assert_eq!(read(3), None);
+
+        let (records, expired) = wal.read_from(1, 10, usize::MAX, 200).unwrap();
+        let payloads: Vec<_> = records.iter().map(|r| r.payload.as_str()).collect();
+        assert_eq!((payloads, expired), (vec!["c"], 1));
+    }
 }
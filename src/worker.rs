@@ -1,19 +1,321 @@
-use tokio::sync::mpsc::Receiver;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::{Receiver, WeakSender};
 use tokio::task::JoinHandle;
 
-use crate::queue::Request;
+use crate::clock;
+use crate::group::GroupOffsets;
+use crate::init::Settings;
+use crate::membership::Membership;
+use crate::memlog::MemLog;
+use crate::protocol::{MsgRef, ResetPolicy, SeekTarget, TopicKind};
+use crate::queue::{
+    CommitResult, FetchError, Fetched, Lag, MemoryTopic, Replayed, Request, SeekResult, TxRecord,
+};
+use crate::schedule::Schedule;
+use crate::stats::Stats;
+use crate::topic::{self, Topic, TopicDefaults};
+use crate::txn::TxLog;
 
 // const WORKER_CONCURRENCY: usize = 8;
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+// retention проверяется раз в RETENTION_EVERY_TICKS тиков (~1 мин)
+const RETENTION_EVERY_TICKS: u64 = 600;
+// отставание групп и ожидание места в очереди пишутся в лог раз в LAG_REPORT_EVERY_TICKS тиков (~10 с)
+const LAG_REPORT_EVERY_TICKS: u64 = 100;
+// исчерпавшие попытки записи уходят в DLQ и без RECEIVE, раз в DLQ_SWEEP_EVERY_TICKS тиков (~1 с)
+const DLQ_SWEEP_EVERY_TICKS: u64 = 10;
+
+pub const SCHEDULE_DIR: &str = "__schedule";
+pub const TX_DIR: &str = "__tx";
+pub const GROUPS_DIR: &str = "__groups";
+
+// заголовки записи в dead-letter топике
+const DLQ_TOPIC_HEADER: &str = "dlq-topic";
+const DLQ_OFFSET_HEADER: &str = "dlq-offset";
+const DLQ_ATTEMPTS_HEADER: &str = "dlq-attempts";
+const DLQ_REASON_HEADER: &str = "dlq-reason";
+const DLQ_PRIO_HEADER: &str = "dlq-prio";
+
+/// Каталог данных и настройки, с которыми открываются топики.
+struct DataDir {
+    path: String,
+    defaults: TopicDefaults,
+}
+
+/// Открывает топик при первом обращении; ошибка диска не роняет worker.
+fn topic_mut<'a>(
+    topics: &'a mut HashMap<String, Topic>,
+    data_dir: &DataDir,
+    name: &str,
+) -> std::io::Result<&'a mut Topic> {
+    match topics.entry(name.to_string()) {
+        Entry::Occupied(e) => Ok(e.into_mut()),
+        Entry::Vacant(e) => Ok(e.insert(Topic::open(&data_dir.path, name, &data_dir.defaults)?)),
+    }
+}
+
+// топик не создаем на FETCH: если WAL-файла нет - считаем, что топика нет
+fn existing_topic<'a>(
+    topics: &'a mut HashMap<String, Topic>,
+    data_dir: &DataDir,
+    name: &str,
+) -> Option<&'a mut Topic> {
+    if !topics.contains_key(name) && !Topic::exists(&data_dir.path, name) {
+        return None;
+    }
+    match topic_mut(topics, data_dir, name) {
+        Ok(t) => Some(t),
+        Err(e) => {
+            // для читателя такой топик - как отсутствующий
+            tracing::error!(topic = %name, error = %e, "topic open failed");
+            None
+        }
+    }
+}
+
+/// Начало и high watermark лога топика, None - топика нет.
+fn log_bounds(
+    topics: &mut HashMap<String, Topic>,
+    mem_topics: &HashMap<String, MemLog>,
+    data_dir: &DataDir,
+    name: &str,
+    prio: u8,
+) -> Option<(u64, u64)> {
+    if let Some(m) = mem_topics.get(name) {
+        return Some(if prio == 0 {
+            (m.start_offset(), m.next_offset())
+        } else {
+            (0, 0)
+        });
+    }
+    let t = existing_topic(topics, data_dir, name)?;
+    Some(
+        t.wal(prio)
+            .map_or((0, 0), |wal| (wal.start_offset(), wal.next_offset())),
+    )
+}
+
+fn high_watermark(
+    topics: &mut HashMap<String, Topic>,
+    mem_topics: &HashMap<String, MemLog>,
+    data_dir: &DataDir,
+    name: &str,
+    prio: u8,
+) -> Option<u64> {
+    log_bounds(topics, mem_topics, data_dir, name, prio).map(|(_, hw)| hw)
+}
+
+/// Offset, с которого читать: `offset`, если он внутри лога, иначе по политике сброса.
+/// Без политики offset берется как есть, а при его отсутствии - начало лога.
+/// None - политика `error`.
+fn reset_offset(
+    offset: Option<u64>,
+    start: u64,
+    hw: u64,
+    policy: Option<ResetPolicy>,
+) -> Option<u64> {
+    match (offset, policy) {
+        (Some(o), _) if (start..=hw).contains(&o) => Some(o),
+        (Some(o), None) => Some(o),
+        (None, None) | (_, Some(ResetPolicy::Earliest)) => Some(start),
+        (_, Some(ResetPolicy::Latest)) => Some(hw),
+        (_, Some(ResetPolicy::Error)) => None,
+    }
+}
 
-pub fn spawn_worker(rx: Receiver<Request>, data_dir: String) -> JoinHandle<()> {
+fn lag_report(
+    groups: &GroupOffsets,
+    topics: &mut HashMap<String, Topic>,
+    mem_topics: &HashMap<String, MemLog>,
+    data_dir: &DataDir,
+    group: Option<&str>,
+    now_ms: u64,
+) -> Vec<Lag> {
+    groups
+        .iter(group)
+        .map(|((group, topic, prio), c)| Lag {
+            group: group.clone(),
+            topic: topic.clone(),
+            prio: *prio,
+            committed: c.offset,
+            high_watermark: high_watermark(topics, mem_topics, data_dir, topic, *prio),
+            since_commit_ms: now_ms.saturating_sub(c.at_ms),
+        })
+        .collect()
+}
+
+/// Переносит в dead-letter топик записи, исчерпавшие число попыток.
+/// Сначала запись попадает в DLQ, затем снимается аренда - при сбое между шагами
+/// запись может оказаться в DLQ дважды (at-least-once).
+fn move_dead_letters(
+    topics: &mut HashMap<String, Topic>,
+    data_dir: &DataDir,
+    name: &str,
+    now_ms: u64,
+) -> std::io::Result<()> {
+    let Some(t) = existing_topic(topics, data_dir, name) else {
+        return Ok(());
+    };
+    let dead = t.dead_letters(now_ms)?;
+    if dead.is_empty() {
+        return Ok(());
+    }
+    let dlq_name = t.dlq_name(name);
+
+    for d in dead {
+        let offset = d.record.offset.to_string();
+        let attempts = d.attempts.to_string();
+        let prio = d.prio.to_string();
+        let mut headers = vec![
+            (DLQ_TOPIC_HEADER, name),
+            (DLQ_OFFSET_HEADER, offset.as_str()),
+            (DLQ_ATTEMPTS_HEADER, attempts.as_str()),
+            (DLQ_REASON_HEADER, d.reason.as_str()),
+        ];
+        if d.prio > 0 {
+            headers.push((DLQ_PRIO_HEADER, prio.as_str()));
+        }
+
+        let dlq = topic_mut(topics, data_dir, &dlq_name)?;
+        let dlq_offset = dlq.wal_mut(0)?.append_with_headers(
+            d.record.id,
+            &d.record.payload,
+            now_ms,
+            None,
+            &headers,
+        )?;
+        tracing::warn!(topic = %name, offset = d.record.offset, dlq = %dlq_name, dlq_offset, reason = %d.reason, "dead-lettered");
+
+        topic_mut(topics, data_dir, name)?.settle(d.prio, d.record.offset, true, None)?;
+    }
+    Ok(())
+}
+
+// записи транзакции, попадающие в один WAL (топик, приоритет)
+type TxGroup = ((String, u8), Vec<(u64, String)>);
+
+/// Фиксирует транзакцию. Записи каждого (топик, приоритет) пишутся одной пачкой
+/// с меткой `tx=<txid>`; транзакция зафиксирована, когда commit-маркер лег в журнал.
+/// При ошибке уже записанные пачки откатываются.
+fn commit_tx(
+    topics: &mut HashMap<String, Topic>,
+    mem_topics: &mut HashMap<String, MemLog>,
+    txlog: &mut TxLog,
+    data_dir: &DataDir,
+    records: Vec<TxRecord>,
+    now_ms: u64,
+) -> std::io::Result<u64> {
+    // группы в порядке первого появления
+    let mut groups: Vec<TxGroup> = Vec::new();
+    for r in records {
+        let key = (r.topic, r.prio);
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, recs)) => recs.push((r.id, r.msg)),
+            None => groups.push((key, vec![(r.id, r.msg)])),
+        }
+    }
+
+    // эфемерные топики в журнал не попадают: откатывать после рестарта нечего
+    let (mem, disk): (Vec<_>, Vec<_>) = groups
+        .into_iter()
+        .partition(|((topic, _), _)| mem_topics.contains_key(topic));
+
+    let participants: Vec<(String, u8)> = disk.iter().map(|(k, _)| k.clone()).collect();
+    let txid = txlog.begin(&participants)?;
+
+    for (i, ((topic, prio), recs)) in disk.iter().enumerate() {
+        let res = topic_mut(topics, data_dir, topic).and_then(|t| {
+            let expires_at = t.expires_at(now_ms, None);
+            t.wal_mut(*prio)?
+                .append_batch(recs, now_ms, expires_at, Some(txid))
+        });
+
+        if let Err(e) = res {
+            for (topic, prio) in &participants[..i] {
+                topic_mut(topics, data_dir, topic)?
+                    .wal_mut(*prio)?
+                    .rollback_tx(txid)?;
+            }
+            txlog.abort(txid)?;
+            return Err(e);
+        }
+    }
+    txlog.commit(txid)?;
+
+    for ((topic, _), recs) in mem {
+        if let Some(m) = mem_topics.get_mut(&topic) {
+            let expires_at = m.expires_at(now_ms, None);
+            m.append_batch(&recs, now_ms, expires_at);
+        }
+    }
+    Ok(txid)
+}
+
+/// Откатывает транзакции, прерванные падением до commit-маркера.
+fn recover_txs(topics: &mut HashMap<String, Topic>, data_dir: &DataDir) -> TxLog {
+    let (mut txlog, pending) =
+        TxLog::open(format!("{}/{}", data_dir.path, TX_DIR)).expect("tx log open failed");
+
+    for tx in pending {
+        for (topic, prio) in &tx.participants {
+            let removed = topic_mut(topics, data_dir, topic)
+                .and_then(|t| t.wal_mut(*prio))
+                .and_then(|wal| wal.rollback_tx(tx.txid))
+                .expect("tx rollback failed");
+            tracing::warn!(txid = tx.txid, topic = %topic, prio, removed, "rolled back unfinished transaction");
+        }
+        txlog.abort(tx.txid).expect("tx log write failed");
+    }
+    txlog
+}
+
+/// Периодически будит worker, чтобы тот перенес созревшие отложенные сообщения.
+/// Держит только weak-ссылку: когда все producers ушли, тикер тоже завершается.
+pub fn spawn_ticker(tx: WeakSender<Request>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(tx) = tx.upgrade() else { break };
+            if tx.send(Request::Tick).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+pub fn spawn_worker(
+    rx: Receiver<Request>,
+    data_dir: String,
+    settings: Settings,
+    stats: Arc<Stats>,
+) -> JoinHandle<()> {
     // Worker is an internal persistence pipeline stub.
     // Message is considered durably accepted once appended to WAL + fsync.
     tokio::task::spawn_blocking(move || {
-        use crate::wal::Wal;
-        use std::collections::HashMap;
-
         let mut rx = rx;
-        let mut wals: HashMap<String, Wal> = HashMap::new();
+        let mut settings = settings;
+        let mut data_dir = DataDir {
+            path: data_dir,
+            defaults: settings.borrow_and_update().topic_defaults.clone(),
+        };
+        let mut topics: HashMap<String, Topic> = HashMap::new();
+        // эфемерные топики: только в памяти, после рестарта их нужно создать заново
+        let mut mem_topics: HashMap<String, MemLog> = HashMap::new();
+        let mut ticks: u64 = 0;
+        let mut txlog = recover_txs(&mut topics, &data_dir);
+
+        let mut schedule = Schedule::open(format!("{}/{}", data_dir.path, SCHEDULE_DIR))
+            .expect("schedule open failed");
+        tracing::info!(pending = schedule.pending_len(), "schedule loaded");
+
+        let mut groups = GroupOffsets::open(format!("{}/{}", data_dir.path, GROUPS_DIR))
+            .expect("group offsets open failed");
+        let mut members = Membership::new(groups.epoch());
 
         while let Some(req) = rx.blocking_recv() {
             match req {
@@ -21,48 +323,697 @@ pub fn spawn_worker(rx: Receiver<Request>, data_dir: String) -> JoinHandle<()> {
                     topic,
                     id,
                     msg,
+                    opts,
                     committed,
                 } => {
-                    let wal = wals.entry(topic.clone()).or_insert_with(|| {
-                        let topic_dir = format!("{}/{}", data_dir, topic);
-                        std::fs::create_dir_all(&topic_dir).expect("topic dir create failed");
-                        let wal_path = format!("{}/wal.log", topic_dir);
-                        Wal::open(&wal_path).expect("wal open failed")
+                    if opts.prio > 0 && mem_topics.contains_key(&topic) {
+                        let _ = committed.send(Err(MemoryTopic));
+                        continue;
+                    }
+
+                    if let Some(due_ms) = opts.not_before
+                        && due_ms > clock::now_ms()
+                    {
+                        match schedule.add(due_ms, &topic, id, &msg, opts.ttl_ms, opts.prio) {
+                            Ok(sid) => {
+                                let _ = committed.send(Ok(None));
+                                tracing::info!(topic = %topic, id, sid, due_ms, "scheduled");
+                            }
+                            Err(e) => {
+                                tracing::error!(topic = %topic, error = %e, "schedule append failed");
+                            }
+                        }
+                        continue;
+                    }
+
+                    let headers: Vec<(&str, &str)> = opts
+                        .headers
+                        .iter()
+                        .map(|(k, v)| (k.as_str(), v.as_str()))
+                        .collect();
+
+                    let now = clock::now_ms();
+                    if let Some(m) = mem_topics.get_mut(&topic) {
+                        let expires_at = m.expires_at(now, opts.ttl_ms);
+                        let offset = m.append(id, &msg, now, expires_at, &headers);
+                        let _ = committed.send(Ok(Some(offset)));
+                        tracing::debug!(topic = %topic, id, offset, "stored in memory");
+                        continue;
+                    }
+
+                    // при ошибке committed дропается - клиент получит ERR WAL
+                    let res = topic_mut(&mut topics, &data_dir, &topic).and_then(|t| {
+                        let expires_at = t.expires_at(now, opts.ttl_ms);
+                        t.wal_mut(opts.prio)?
+                            .append_with_headers(id, &msg, now, expires_at, &headers)
+                    });
+                    match res {
+                        Ok(offset) => {
+                            let _ = committed.send(Ok(Some(offset)));
+                            tracing::info!(topic = %topic, id, offset, "stored");
+                        }
+                        Err(e) => {
+                            tracing::error!(topic = %topic, id, error = %e, "append failed");
+                        }
+                    }
+                }
+
+                Request::Tick => {
+                    let now = clock::now_ms();
+
+                    // конфиг перечитан по SIGHUP
+                    if settings.has_changed().unwrap_or(false) {
+                        data_dir.defaults = settings.borrow_and_update().topic_defaults.clone();
+                        for t in topics.values_mut() {
+                            t.set_defaults(&data_dir.defaults);
+                        }
+                        tracing::info!("topic defaults updated");
+                    }
+
+                    for (group, member) in members.expire(now) {
+                        tracing::warn!(group = %group, member = %member, "member session expired");
+                    }
+
+                    for m in schedule.take_due(now) {
+                        if let Some(mem) = mem_topics.get_mut(&m.topic) {
+                            let expires_at = mem.expires_at(now, m.ttl_ms);
+                            mem.append(m.id, &m.msg, now, expires_at, &[]);
+                            if let Err(e) = schedule.mark_delivered(m.sid) {
+                                tracing::error!(sid = m.sid, error = %e, "schedule mark failed");
+                            }
+                            continue;
+                        }
+
+                        let res = topic_mut(&mut topics, &data_dir, &m.topic).and_then(|t| {
+                            let expires_at = t.expires_at(now, m.ttl_ms);
+                            t.wal_mut(m.prio)?.append_msg(m.id, &m.msg, now, expires_at)
+                        });
+                        match res {
+                            Ok(offset) => {
+                                tracing::info!(topic = %m.topic, id = m.id, offset, "scheduled message delivered");
+                                if let Err(e) = schedule.mark_delivered(m.sid) {
+                                    // при рестарте сообщение будет доставлено повторно (at-least-once)
+                                    tracing::error!(sid = m.sid, error = %e, "schedule mark failed");
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!(topic = %m.topic, error = %e, "scheduled delivery failed");
+                                schedule.restore(m);
+                            }
+                        }
+                    }
+
+                    ticks += 1;
+                    if ticks.is_multiple_of(DLQ_SWEEP_EVERY_TICKS) {
+                        // только загруженные топики; остальные разберет первый RECEIVE
+                        let names: Vec<String> = topics.keys().cloned().collect();
+                        for name in names {
+                            if let Err(e) = move_dead_letters(&mut topics, &data_dir, &name, now) {
+                                tracing::error!(topic = %name, error = %e, "dead-letter move failed");
+                            }
+                        }
+                    }
+
+                    if ticks.is_multiple_of(LAG_REPORT_EVERY_TICKS) {
+                        let lags =
+                            lag_report(&groups, &mut topics, &mem_topics, &data_dir, None, now);
+                        for l in lags {
+                            tracing::info!(
+                                group = %l.group,
+                                topic = %l.topic,
+                                partition = l.prio,
+                                committed = l.committed,
+                                high_watermark = ?l.high_watermark,
+                                lag = ?l.lag(),
+                                since_commit_ms = l.since_commit_ms,
+                                "consumer lag"
+                            );
+                        }
+
+                        // счетчики накопительные, как в итоговом логе при остановке
+                        let (blocked, blocked_ms) = stats.blocked();
+                        if blocked > 0 {
+                            tracing::info!(blocked, blocked_ms, "backpressure stats");
+                        }
+                    }
+
+                    if ticks.is_multiple_of(RETENTION_EVERY_TICKS) {
+                        for (name, t) in topics.iter_mut() {
+                            for (prio, wal) in t.wals_mut() {
+                                match wal.delete_expired_segments(now) {
+                                    Ok(0) => {}
+                                    Ok(n) => {
+                                        tracing::info!(topic = %name, prio, segments = n, "expired segments deleted")
+                                    }
+                                    Err(e) => {
+                                        tracing::error!(topic = %name, prio, error = %e, "retention failed")
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                Request::ProduceBatch {
+                    topic,
+                    prio,
+                    records,
+                    committed,
+                } => {
+                    let now = clock::now_ms();
+                    if let Some(m) = mem_topics.get_mut(&topic) {
+                        if prio > 0 {
+                            let _ = committed.send(Err(MemoryTopic));
+                            continue;
+                        }
+                        let expires_at = m.expires_at(now, None);
+                        // пустую пачку не подтверждаем: committed дропается, как при ошибке WAL
+                        if let Some(range) = m.append_batch(&records, now, expires_at) {
+                            let _ = committed.send(Ok(range));
+                        }
+                        continue;
+                    }
+
+                    // при ошибке committed дропается - клиент получит ERR WAL
+                    let res = topic_mut(&mut topics, &data_dir, &topic).and_then(|t| {
+                        let expires_at = t.expires_at(now, None);
+                        t.wal_mut(prio)?
+                            .append_batch(&records, now, expires_at, None)
                     });
+                    match res {
+                        Ok((first, last)) => {
+                            let _ = committed.send(Ok((first, last)));
+                            tracing::info!(topic = %topic, first, last, "batch stored");
+                        }
+                        Err(e) => {
+                            tracing::error!(topic = %topic, error = %e, "batch append failed");
+                        }
+                    }
+                }
 
-                    let _offset = wal.append_msg(id, &msg).expect("wal append failed");
-                    let _ = committed.send(());
-                    tracing::info!(topic = %topic, id, "stored");
+                Request::Commit { records, committed } => {
+                    let count = records.len();
+                    let res = commit_tx(
+                        &mut topics,
+                        &mut mem_topics,
+                        &mut txlog,
+                        &data_dir,
+                        records,
+                        clock::now_ms(),
+                    );
+                    // при ошибке committed дропается - клиент получит ERR WAL
+                    match res {
+                        Ok(txid) => {
+                            let _ = committed.send(txid);
+                            tracing::info!(txid, count, "transaction stored");
+                        }
+                        Err(e) => {
+                            tracing::error!(error = %e, "transaction commit failed");
+                        }
+                    }
+                }
+
+                Request::CommitOffset {
+                    group,
+                    topic,
+                    prio,
+                    offset,
+                    generation,
+                    reply,
+                } => {
+                    if !members.check_generation(&group, generation) {
+                        let _ = reply.send(CommitResult::StaleGeneration);
+                        continue;
+                    }
+
+                    let res = match high_watermark(
+                        &mut topics,
+                        &mem_topics,
+                        &data_dir,
+                        &topic,
+                        prio,
+                    ) {
+                        None => CommitResult::UnknownTopic,
+                        Some(hw) if offset > hw => CommitResult::OutOfRange,
+                        Some(_) => {
+                            match groups.commit(&group, &topic, prio, offset, clock::now_ms()) {
+                                Ok(()) => CommitResult::Committed,
+                                Err(e) => {
+                                    // reply дропается - клиент получит ERR WAL
+                                    tracing::error!(group = %group, topic = %topic, error = %e, "offset commit failed");
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    let _ = reply.send(res);
+                }
+
+                Request::Join {
+                    group,
+                    member,
+                    topics,
+                    session_timeout_ms,
+                    strategy,
+                    reply,
+                } => {
+                    let now = clock::now_ms();
+                    let res =
+                        members.join(&group, &member, topics, session_timeout_ms, strategy, now);
+                    if let Some(a) = &res {
+                        tracing::info!(group = %group, member = %member, generation = a.generation, topics = ?a.topics, "member joined");
+                    }
+                    let _ = reply.send(res);
+                }
+
+                Request::Heartbeat {
+                    group,
+                    member,
+                    reply,
+                } => {
+                    let _ = reply.send(members.heartbeat(&group, &member, clock::now_ms()));
+                }
+
+                Request::Leave {
+                    group,
+                    member,
+                    reply,
+                } => {
+                    let left = members.leave(&group, &member);
+                    if left {
+                        tracing::info!(group = %group, member = %member, "member left");
+                    }
+                    let _ = reply.send(left);
+                }
+
+                Request::Lag { group, reply } => {
+                    let lags = lag_report(
+                        &groups,
+                        &mut topics,
+                        &mem_topics,
+                        &data_dir,
+                        group.as_deref(),
+                        clock::now_ms(),
+                    );
+                    let _ = reply.send(lags);
+                }
+
+                Request::Topics { reply } => {
+                    let mut out: Vec<(String, TopicKind)> = topic::list_topics(&data_dir.path)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|name| (name, TopicKind::Disk))
+                        .chain(
+                            mem_topics
+                                .keys()
+                                .map(|name| (name.clone(), TopicKind::Memory)),
+                        )
+                        .collect();
+                    out.sort_by(|a, b| a.0.cmp(&b.0));
+                    let _ = reply.send(out);
                 }
 
                 Request::Fetch {
                     topic,
                     from,
                     limit,
+                    max_bytes,
+                    prio,
+                    group,
+                    reset,
+                    reply,
+                } => {
+                    if prio > 0 && mem_topics.contains_key(&topic) {
+                        let _ = reply.send(Err(FetchError::MemoryTopic));
+                        continue;
+                    }
+                    let Some((start, hw)) =
+                        log_bounds(&mut topics, &mem_topics, &data_dir, &topic, prio)
+                    else {
+                        let _ = reply.send(Err(FetchError::UnknownTopic));
+                        continue;
+                    };
+
+                    // явный offset важнее закоммиченного, политика FETCH - политики группы
+                    let committed = group
+                        .as_deref()
+                        .and_then(|g| groups.get(g, &topic, prio))
+                        .map(|c| c.offset);
+                    let policy =
+                        reset.or_else(|| group.as_deref().and_then(|g| groups.reset_policy(g)));
+                    let Some(from) = reset_offset(from.or(committed), start, hw, policy) else {
+                        let _ = reply.send(Err(FetchError::OffsetOutOfRange));
+                        continue;
+                    };
+
+                    let now = clock::now_ms();
+                    let (records, expired) = match mem_topics.get(&topic) {
+                        Some(m) => m.read_from(from, limit, max_bytes, now),
+                        // уровень приоритета, в который еще не писали, - пустой лог
+                        None => existing_topic(&mut topics, &data_dir, &topic)
+                            .and_then(|t| t.wal(prio))
+                            .map(|wal| {
+                                wal.read_from(from, limit, max_bytes, now)
+                                    .unwrap_or_default()
+                            })
+                            .unwrap_or_default(),
+                    };
+                    stats.add_expired_skips(expired);
+
+                    let _ = reply.send(Ok(Fetched {
+                        from,
+                        records,
+                        start_offset: start,
+                        next_offset: hw,
+                    }));
+                }
+
+                Request::ConfigureGroup {
+                    group,
+                    reset,
+                    reply,
+                } => match groups.set_reset_policy(&group, reset) {
+                    Ok(()) => {
+                        let _ = reply.send(());
+                    }
+                    Err(e) => {
+                        // reply дропается - клиент получит ERR WAL
+                        tracing::error!(group = %group, error = %e, "group config write failed");
+                    }
+                },
+
+                Request::Seek {
+                    group,
+                    topic,
+                    prio,
+                    target,
+                    reply,
+                } => {
+                    // двигать offset можно только остановленной группе
+                    if !members.check_generation(&group, None) {
+                        let _ = reply.send(SeekResult::GroupActive);
+                        continue;
+                    }
+                    let Some((start, hw)) =
+                        log_bounds(&mut topics, &mem_topics, &data_dir, &topic, prio)
+                    else {
+                        let _ = reply.send(SeekResult::UnknownTopic);
+                        continue;
+                    };
+
+                    let offset = match target {
+                        SeekTarget::Offset(o) if (start..=hw).contains(&o) => Ok(o),
+                        SeekTarget::Offset(_) => {
+                            let _ = reply.send(SeekResult::OutOfRange);
+                            continue;
+                        }
+                        SeekTarget::Earliest => Ok(start),
+                        SeekTarget::Latest => Ok(hw),
+                        SeekTarget::Time(ts) => match mem_topics.get(&topic) {
+                            Some(m) if prio == 0 => Ok(m.offset_for_time(ts)),
+                            Some(_) => Ok(hw),
+                            None => existing_topic(&mut topics, &data_dir, &topic)
+                                .and_then(|t| t.wal(prio))
+                                .map_or(Ok(hw), |wal| wal.offset_for_time(ts)),
+                        },
+                    };
+
+                    let now = clock::now_ms();
+                    let res = offset.and_then(|offset| {
+                        groups
+                            .commit(&group, &topic, prio, offset, now)
+                            .map(|_| offset)
+                    });
+                    match res {
+                        Ok(offset) => {
+                            tracing::info!(group = %group, topic = %topic, prio, offset, "group offset moved");
+                            let _ = reply.send(SeekResult::Seeked(offset));
+                        }
+                        Err(e) => {
+                            // reply дропается - клиент получит ERR WAL
+                            tracing::error!(group = %group, topic = %topic, error = %e, "seek failed");
+                        }
+                    }
+                }
+
+                Request::Receive {
+                    topic,
+                    visibility_ms,
+                    reply,
+                } => {
+                    // у эфемерного топика нет аренд: читать его можно только FETCH и POLL
+                    if mem_topics.contains_key(&topic) {
+                        let _ = reply.send(Err(MemoryTopic));
+                        continue;
+                    }
+
+                    let now = clock::now_ms();
+                    if let Err(e) = move_dead_letters(&mut topics, &data_dir, &topic, now) {
+                        tracing::error!(topic = %topic, error = %e, "dead-letter move failed");
+                    }
+
+                    let leased = match existing_topic(&mut topics, &data_dir, &topic) {
+                        Some(t) => match t.receive(now, visibility_ms) {
+                            Ok(v) => v.map(|(prio, r, attempts)| {
+                                (
+                                    MsgRef {
+                                        prio,
+                                        offset: r.offset,
+                                    },
+                                    r,
+                                    attempts,
+                                )
+                            }),
+                            Err(e) => {
+                                tracing::error!(topic = %topic, error = %e, "receive failed");
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    let _ = reply.send(Ok(leased));
+                }
+
+                Request::Settle {
+                    topic,
+                    msg,
+                    ack,
+                    reason,
+                    reply,
+                } => {
+                    let settled = match existing_topic(&mut topics, &data_dir, &topic) {
+                        Some(t) => match t.settle(msg.prio, msg.offset, ack, reason.as_deref()) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                tracing::error!(topic = %topic, error = %e, "settle failed");
+                                continue;
+                            }
+                        },
+                        None => false,
+                    };
+
+                    let _ = reply.send(settled);
+                }
+
+                Request::Replay {
+                    topic,
+                    from,
+                    limit,
+                    reply,
+                } => {
+                    let now = clock::now_ms();
+                    let wal = existing_topic(&mut topics, &data_dir, &topic).and_then(|t| t.wal(0));
+                    let records = match wal {
+                        Some(wal) => {
+                            wal.read_from(from, limit, usize::MAX, now)
+                                .unwrap_or_default()
+                                .0
+                        }
+                        None => Vec::new(),
+                    };
+
+                    let mut replayed = Vec::new();
+                    for r in records {
+                        // без заголовка исходного топика запись не из DLQ - пропускаем
+                        let Some((_, origin)) =
+                            r.headers.iter().find(|(k, _)| k == DLQ_TOPIC_HEADER)
+                        else {
+                            continue;
+                        };
+
+                        let prio = r
+                            .headers
+                            .iter()
+                            .find(|(k, _)| k == DLQ_PRIO_HEADER)
+                            .and_then(|(_, v)| v.parse::<u8>().ok())
+                            .unwrap_or(0);
+
+                        if let Some(m) = mem_topics.get_mut(origin) {
+                            let expires_at = m.expires_at(now, None);
+                            let offset = m.append(r.id, &r.payload, now, expires_at, &[]);
+                            replayed.push(Replayed {
+                                dlq_offset: r.offset,
+                                topic: origin.clone(),
+                                offset,
+                            });
+                            continue;
+                        }
+
+                        let res = topic_mut(&mut topics, &data_dir, origin).and_then(|t| {
+                            let expires_at = t.expires_at(now, None);
+                            t.wal_mut(prio)?
+                                .append_msg(r.id, &r.payload, now, expires_at)
+                        });
+                        match res {
+                            Ok(offset) => replayed.push(Replayed {
+                                dlq_offset: r.offset,
+                                topic: origin.clone(),
+                                offset,
+                            }),
+                            Err(e) => {
+                                tracing::error!(topic = %origin, error = %e, "replay append failed");
+                                break;
+                            }
+                        }
+                    }
+
+                    tracing::info!(dlq = %topic, count = replayed.len(), "replayed");
+                    let _ = reply.send(replayed);
+                }
+
+                Request::Poll {
+                    patterns,
+                    cursors,
+                    start,
+                    limit,
                     reply,
                 } => {
-                    let entries = match wals.get_mut(&topic) {
-                        Some(wal) => wal.read_from(from, limit).unwrap_or_default(),
-                        None => {
-                            // топик не создаем на FETCH
-                            let wal_path = format!("{}/{}/wal.log", data_dir, topic);
-
-                            // если файла нет - считаем, что топика нет
-                            if std::path::Path::new(&wal_path).exists() {
-                                let wal = Wal::open(&wal_path).expect("wal open failed");
-                                let res = wal.read_from(from, limit).unwrap_or_default();
-                                wals.insert(topic.clone(), wal);
-                                res
-                            } else {
-                                Vec::new()
+                    // топики перечитываются на каждый POLL - так подхватываются и новые
+                    let mut names: Vec<String> = topic::list_topics(&data_dir.path)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .chain(mem_topics.keys().cloned())
+                        .filter(|name| patterns.iter().any(|p| topic::matches(p, name)))
+                        .collect();
+                    names.sort();
+                    names.dedup();
+                    if !names.is_empty() {
+                        let n = names.len();
+                        names.rotate_left(start % n);
+                    }
+
+                    let now = clock::now_ms();
+                    let mut out = Vec::new();
+                    for name in names {
+                        if out.len() >= limit {
+                            break;
+                        }
+
+                        if let Some(m) = mem_topics.get(&name) {
+                            let from = cursors.get(&(name.clone(), 0)).copied().unwrap_or(0);
+                            let (entries, expired) =
+                                m.read_from(from, limit - out.len(), usize::MAX, now);
+                            stats.add_expired_skips(expired);
+                            out.extend(entries.into_iter().map(|e| (name.clone(), 0, e)));
+                            continue;
+                        }
+
+                        let Some(t) = existing_topic(&mut topics, &data_dir, &name) else {
+                            continue;
+                        };
+                        // уровни от старшего к младшему, как в RECEIVE
+                        for (prio, wal) in t.wals().rev() {
+                            if out.len() >= limit {
+                                break;
                             }
+                            let from = cursors.get(&(name.clone(), prio)).copied().unwrap_or(0);
+                            let (entries, expired) = wal
+                                .read_from(from, limit - out.len(), usize::MAX, now)
+                                .unwrap_or_default();
+                            stats.add_expired_skips(expired);
+                            out.extend(entries.into_iter().map(|e| (name.clone(), prio, e)));
+                        }
+                    }
+
+                    let _ = reply.send(out);
+                }
+
+                Request::CreateTopic { topic, opts, reply } => {
+                    let is_mem = mem_topics.contains_key(&topic);
+                    let is_disk =
+                        topics.contains_key(&topic) || Topic::exists(&data_dir.path, &topic);
+
+                    // тип топика выбирается при создании и дальше не меняется
+                    let kind = opts.kind.unwrap_or(if is_mem {
+                        TopicKind::Memory
+                    } else {
+                        TopicKind::Disk
+                    });
+                    if (kind == TopicKind::Memory && is_disk) || (kind == TopicKind::Disk && is_mem)
+                    {
+                        let _ = reply.send(false);
+                        continue;
+                    }
+
+                    if kind == TopicKind::Memory {
+                        let m = mem_topics.entry(topic.clone()).or_insert_with(|| {
+                            MemLog::new(
+                                opts.capacity.unwrap_or(data_dir.defaults.mem_capacity),
+                                data_dir.defaults.ttl_ms,
+                            )
+                        });
+                        if opts.ttl_ms.is_some() {
+                            m.ttl_ms = opts.ttl_ms;
+                        }
+                        if let Some(capacity) = opts.capacity {
+                            m.set_capacity(capacity);
+                        }
+                        let _ = reply.send(true);
+                        tracing::info!(topic = %topic, "memory topic configured");
+                        continue;
+                    }
+
+                    // reply дропается - клиент получит ERR WAL
+                    let t = match topic_mut(&mut topics, &data_dir, &topic) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            tracing::error!(topic = %topic, error = %e, "topic open failed");
+                            continue;
                         }
                     };
+                    if opts.ttl_ms.is_some() {
+                        t.conf.ttl_ms = opts.ttl_ms;
+                    }
+                    if opts.max_attempts.is_some() {
+                        t.conf.max_attempts = opts.max_attempts;
+                    }
+                    if opts.dlq.is_some() {
+                        t.conf.dlq = opts.dlq;
+                    }
+
+                    match t.conf.save(&t.dir) {
+                        Ok(()) => {
+                            let _ = reply.send(true);
+                            tracing::info!(topic = %topic, "topic configured");
+                        }
+                        Err(e) => {
+                            tracing::error!(topic = %topic, error = %e, "topic config save failed");
+                        }
+                    }
+                }
+            }
+        }
 
-                    let _ = reply.send(entries);
+        // очередь пуста и отправителей нет: финальный sync всех WAL перед выходом
+        for (name, t) in topics.iter_mut() {
+            for (prio, wal) in t.wals_mut() {
+                if let Err(e) = wal.sync() {
+                    tracing::error!(topic = %name, prio, error = %e, "final wal sync failed");
                 }
             }
         }
+        tracing::info!(topics = topics.len(), "wal synced");
 
         tracing::info!("worker stopped");
     })
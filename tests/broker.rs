@@ -0,0 +1,136 @@
+mod common;
+
+use samovaroff_broker::Broker;
+use samovaroff_broker::client::{Client, ClientError};
+use samovaroff_broker::config::RuntimeConfig;
+use samovaroff_broker::protocol::ErrorCode;
+
+use common::start;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn port_zero_reports_bound_addr() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+
+    let addr = broker.local_addr();
+    assert!(addr.ip().is_loopback());
+    assert_ne!(addr.port(), 0);
+    Client::connect(&addr.to_string())
+        .await
+        .unwrap()
+        .ping()
+        .await
+        .unwrap();
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn in_process_publish_and_fetch() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+
+    assert_eq!(broker.publish("jobs", "a").await.unwrap(), 0);
+    assert_eq!(
+        broker.publish_batch("jobs", &["b", "c"]).await.unwrap(),
+        (1, 2)
+    );
+
+    let page = broker.fetch("jobs", 1, 10).await.unwrap();
+    let payloads: Vec<_> = page.records.iter().map(|r| r.payload.as_str()).collect();
+    assert_eq!(payloads, ["b", "c"]);
+    assert_eq!((page.next, page.high_watermark, page.log_start), (3, 3, 0));
+
+    // то же видно и по TCP
+    let client = Client::connect(&broker.local_addr().to_string())
+        .await
+        .unwrap();
+    let page = client.fetch("jobs", 0, 10).await.unwrap();
+    assert_eq!(page.records.len(), 3);
+
+    match broker.fetch("missing", 0, 10).await {
+        Err(ClientError::Broker(ErrorCode::UnknownTopic, _)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("fetch of a missing topic succeeded"),
+    }
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_applies_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    broker.publish("t", "0123456789").await.unwrap();
+
+    let mut runtime = RuntimeConfig::default();
+    runtime.limits.max_msg_bytes = 4;
+    broker.reload(runtime);
+
+    match broker.publish("t", "0123456789").await {
+        Err(ClientError::Broker(ErrorCode::TooLarge, _)) => {}
+        other => panic!("expected TOO_LARGE, got {:?}", other.map(|_| ())),
+    }
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn data_survives_restart() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let broker = start(dir.path()).await;
+    broker.publish("kept", "x").await.unwrap();
+    broker.shutdown().await;
+
+    let broker = start(dir.path()).await;
+    assert_eq!(broker.publish("kept", "y").await.unwrap(), 1);
+    let page = broker.fetch("kept", 0, 10).await.unwrap();
+    let payloads: Vec<_> = page.records.iter().map(|r| r.payload.as_str()).collect();
+    assert_eq!(payloads, ["x", "y"]);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_batches_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+
+    for payloads in [&[][..], &["a\nb"][..], &["ok", "cr\r"][..]] {
+        match broker.publish_batch("t", payloads).await {
+            Err(ClientError::Broker(ErrorCode::BadRequest, _)) => {}
+            other => panic!("expected BAD_REQUEST, got {:?}", other),
+        }
+    }
+    // брокер жив и пишет дальше
+    assert_eq!(broker.publish("t", "x").await.unwrap(), 0);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fetch_stops_at_byte_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut runtime = RuntimeConfig::default();
+    runtime.limits.max_msg_bytes = 1024;
+    runtime.limits.max_fetch_bytes = 4096;
+    let broker = Broker::builder()
+        .data_dir(dir.path())
+        .runtime(runtime)
+        .start()
+        .await
+        .unwrap();
+
+    let payload = "x".repeat(1000);
+    for _ in 0..50 {
+        broker.publish("big", &payload).await.unwrap();
+    }
+
+    // limit огромный, но worker читает только то, что влезет в ответ
+    let page = broker.fetch("big", 0, usize::MAX).await.unwrap();
+    assert!(!page.records.is_empty() && page.records.len() <= 5);
+    assert_eq!(page.next, page.records.len() as u64);
+
+    broker.shutdown().await;
+}
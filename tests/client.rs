@@ -0,0 +1,200 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio_stream::StreamExt;
+
+use samovaroff_broker::Broker;
+use samovaroff_broker::client::{Client, ClientError, ClientOpts};
+use samovaroff_broker::protocol::{Backpressure, ErrorCode, Feature};
+
+use common::{start, start_at};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publish_then_fetch() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let addr = broker.local_addr().to_string();
+    let client = Client::connect(&addr).await.unwrap();
+
+    client.ping().await.unwrap();
+    assert_eq!(client.publish("orders", "a").await.unwrap(), 0);
+    assert_eq!(client.publish("orders", "b").await.unwrap(), 1);
+    assert_eq!(
+        client.publish_batch("orders", &["c", "d"]).await.unwrap(),
+        (2, 3)
+    );
+
+    let page = client.fetch("orders", 1, 2).await.unwrap();
+    let payloads: Vec<_> = page.records.iter().map(|r| r.payload.as_str()).collect();
+    assert_eq!(payloads, ["b", "c"]);
+    assert_eq!(page.records[0].offset, 1);
+    assert_eq!(page.next, 3);
+    assert_eq!(page.high_watermark, 4);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fetch_unknown_topic_is_broker_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let addr = broker.local_addr().to_string();
+    let client = Client::connect(&addr).await.unwrap();
+
+    match client.fetch("missing", 0, 10).await {
+        Err(ClientError::Broker(ErrorCode::UnknownTopic, _)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("fetch of a missing topic succeeded"),
+    }
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pipelined_publishes_get_distinct_offsets() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let addr = broker.local_addr().to_string();
+    let client = Client::connect(&addr).await.unwrap();
+
+    let tasks: Vec<_> = (0..50)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move { client.publish("events", &format!("m{}", i)).await })
+        })
+        .collect();
+    let mut offsets = Vec::new();
+    for t in tasks {
+        offsets.push(t.await.unwrap().unwrap());
+    }
+    offsets.sort();
+    assert_eq!(offsets, (0..50).collect::<Vec<u64>>());
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribe_sees_old_and_new_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let addr = broker.local_addr().to_string();
+    let client = Client::connect(&addr).await.unwrap();
+
+    client.publish("feed", "old").await.unwrap();
+    let mut sub = client.subscribe("feed", 0);
+    let first = sub.next().await.unwrap().unwrap();
+    assert_eq!((first.offset, first.payload.as_str()), (0, "old"));
+
+    client.publish("feed", "new").await.unwrap();
+    let second = tokio::time::timeout(Duration::from_secs(5), sub.next())
+        .await
+        .expect("no record within 5s")
+        .unwrap()
+        .unwrap();
+    assert_eq!((second.offset, second.payload.as_str()), (1, "new"));
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_after_broker_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let addr = broker.local_addr().to_string();
+    let opts = ClientOpts {
+        initial_backoff: Duration::from_millis(20),
+        ..ClientOpts::default()
+    };
+    let client = Client::connect_with(&addr, opts).await.unwrap();
+    assert_eq!(client.publish("log", "before").await.unwrap(), 0);
+
+    broker.shutdown().await;
+    // тот же адрес: клиент переподключается туда же
+    let broker = start_at(dir.path(), &addr).await;
+
+    // запрос после рестарта уходит уже по новому соединению
+    assert_eq!(client.publish("log", "after").await.unwrap(), 1);
+    let page = client.fetch("log", 0, 10).await.unwrap();
+    let payloads: Vec<_> = page.records.iter().map(|r| r.payload.as_str()).collect();
+    assert_eq!(payloads, ["before", "after"]);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pause_backpressure_waits_instead_of_rejecting() {
+    let dir = tempfile::tempdir().unwrap();
+    // очередь на один запрос: без ожидания параллельные записи получали бы QUEUE_FULL
+    let broker = Broker::builder()
+        .data_dir(dir.path())
+        .queue_capacity(1)
+        .start()
+        .await
+        .unwrap();
+    let addr = broker.local_addr().to_string();
+    let opts = ClientOpts {
+        backpressure: Some(Backpressure::Pause),
+        ..ClientOpts::default()
+    };
+
+    let mut tasks = Vec::new();
+    for c in 0..8 {
+        let client = Client::connect_with(&addr, opts.clone()).await.unwrap();
+        tasks.push(tokio::spawn(async move {
+            for i in 0..50 {
+                client
+                    .publish("pressure", &format!("{}-{}", c, i))
+                    .await
+                    .unwrap();
+            }
+        }));
+    }
+    for t in tasks {
+        t.await.unwrap();
+    }
+
+    let page = broker.fetch("pressure", 0, 1000).await.unwrap();
+    assert_eq!(page.records.len(), 400);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_negotiates_all_features() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = Broker::builder()
+        .data_dir(dir.path())
+        .node_id("n7")
+        .start()
+        .await
+        .unwrap();
+    let addr = broker.local_addr().to_string();
+
+    let client = Client::connect(&addr).await.unwrap();
+    let server = client.server().expect("broker answered HELLO");
+    assert_eq!((server.version, server.node_id.as_str()), (1, "n7"));
+    assert_eq!(server.features, Feature::ALL);
+    // ACK на PUB с offset-ом клиент разбирает сам
+    assert_eq!(client.publish("t", "x").await.unwrap(), 0);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn multi_line_payload_is_not_sent() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let addr = broker.local_addr().to_string();
+    let client = Client::connect(&addr).await.unwrap();
+
+    match client.publish("t", "a\nPING").await {
+        Err(ClientError::Invalid(_)) => {}
+        other => panic!("expected Invalid, got {:?}", other),
+    }
+    // соединение не рассинхронизировано: ответы идут своим запросам
+    assert_eq!(client.publish("t", "b").await.unwrap(), 0);
+    assert_eq!(client.fetch("t", 0, 10).await.unwrap().records.len(), 1);
+
+    broker.shutdown().await;
+}
@@ -0,0 +1,73 @@
+//! Общее для интеграционных тестов: запуск брокера и клиент строкового протокола.
+
+// каждый тестовый крейт берет отсюда только свою часть
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use samovaroff_broker::protocol::Response;
+use samovaroff_broker::{Broker, BrokerHandle};
+
+pub async fn start(data_dir: &Path) -> BrokerHandle {
+    Broker::builder().data_dir(data_dir).start().await.unwrap()
+}
+
+/// Брокер на заданном адресе - для рестарта на том же порту.
+pub async fn start_at(data_dir: &Path, bind: &str) -> BrokerHandle {
+    Broker::builder()
+        .data_dir(data_dir)
+        .bind(bind)
+        .start()
+        .await
+        .unwrap()
+}
+
+/// Соединение без `Client`: строки уходят как есть, ответы читаются по одной строке.
+pub struct LineClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    w: OwnedWriteHalf,
+}
+
+impl LineClient {
+    pub async fn connect(broker: &BrokerHandle) -> Self {
+        let stream = TcpStream::connect(broker.local_addr()).await.unwrap();
+        let (r, w) = stream.into_split();
+        LineClient {
+            lines: BufReader::new(r).lines(),
+            w,
+        }
+    }
+
+    /// Пишет строки без ожидания ответа; `\n` в конце каждой - на вызывающем.
+    pub async fn send(&mut self, data: &str) {
+        self.w.write_all(data.as_bytes()).await.unwrap();
+    }
+
+    pub async fn line(&mut self) -> String {
+        self.lines.next_line().await.unwrap().unwrap()
+    }
+
+    /// Команда, на которую приходит ровно одна строка ответа.
+    pub async fn call(&mut self, cmd: &str) -> Response {
+        self.send(&format!("{}\n", cmd)).await;
+        let line = self.line().await;
+        Response::parse(&line).unwrap_or_else(|| panic!("{}: bad reply {}", cmd, line))
+    }
+
+    /// Строки данных до итоговой `OK`/`ACK`/`ERR`.
+    pub async fn call_data(&mut self, cmd: &str) -> (Vec<String>, Response) {
+        self.send(&format!("{}\n", cmd)).await;
+        let mut data = Vec::new();
+        loop {
+            let line = self.line().await;
+            if ["OK", "ACK", "ERR"].iter().any(|p| line.starts_with(p)) {
+                return (data, Response::parse(&line).unwrap());
+            }
+            data.push(line);
+        }
+    }
+}
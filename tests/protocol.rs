@@ -0,0 +1,244 @@
+mod common;
+
+use std::time::Duration;
+
+use samovaroff_broker::config::RuntimeConfig;
+use samovaroff_broker::protocol::{ErrorCode, Feature, Response};
+use samovaroff_broker::{Broker, BrokerHandle};
+
+use common::{LineClient, start};
+
+// correlation-id запроса REQUEST с таким payload-ом, когда он появится в очереди
+async fn correlation_id(broker: &BrokerHandle, topic: &str, payload: &str) -> String {
+    loop {
+        let page = broker.fetch(topic, 0, 100).await.unwrap();
+        let found = page.records.into_iter().find(|r| r.payload == payload);
+        if let Some((_, id)) =
+            found.and_then(|r| r.headers.into_iter().find(|(k, _)| k == "correlation-id"))
+        {
+            return id;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn errors_carry_code_and_retriable_flag() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let mut conn = LineClient::connect(&broker).await;
+
+    for (cmd, code) in [
+        ("BOGUS", ErrorCode::UnknownCommand),
+        ("COMMIT", ErrorCode::InvalidState),
+        ("FETCH missing 0 10", ErrorCode::UnknownTopic),
+        // известная команда с плохими аргументами - не UNKNOWN_COMMAND
+        ("FETCH t x 10", ErrorCode::BadRequest),
+        ("FETCH t 0 10 MAXBYTES=0", ErrorCode::BadRequest),
+        ("PUB t PRIO=12 hello", ErrorCode::BadRequest),
+        ("SEEK g t nowhere", ErrorCode::BadRequest),
+        ("FETCH", ErrorCode::BadRequest),
+        // имя топика - каталог в data_dir: ни путей, ни служебных имен
+        ("PUB x/p3 a", ErrorCode::BadRequest),
+        ("PUB ../etc a", ErrorCode::BadRequest),
+        ("PUB a..b a", ErrorCode::BadRequest),
+        ("PUB __tx a", ErrorCode::BadRequest),
+        ("CREATE q DLQ=__groups", ErrorCode::BadRequest),
+        ("FETCH a\\b 0 10", ErrorCode::BadRequest),
+    ] {
+        conn.send(&format!("{}\n", cmd)).await;
+        let line = conn.line().await;
+        assert!(
+            line.starts_with(&format!("ERR {} retriable=false ", code)),
+            "{}: {}",
+            cmd,
+            line
+        );
+        let r = Response::parse(&line).unwrap();
+        assert_eq!(r.error_code(), Some(code));
+    }
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_backpressure_policy_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let mut conn = LineClient::connect(&broker).await;
+
+    let r = conn.call("BACKPRESSURE block:0").await;
+    assert_eq!(r.error_code(), Some(ErrorCode::BadRequest));
+    assert_eq!(conn.call("BACKPRESSURE block:250").await, Response::Ok);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hello_negotiates_features() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let mut conn = LineClient::connect(&broker).await;
+
+    // версия новее брокерской сводится к его; незнакомую возможность брокер не подтверждает
+    let Response::Hello(hello) = conn.call("HELLO 7 raw acks teleport").await else {
+        panic!("no hello reply");
+    };
+    assert_eq!((hello.version, hello.features), (1, vec![Feature::Acks]));
+    assert_eq!(conn.call("PUB t x").await, Response::AckRange(0, 0));
+    // MPUB работает без согласования
+    conn.send("MPUB t 1\n").await;
+    assert_eq!(conn.call("y").await, Response::AckRange(1, 1));
+    let r = conn.call("HELLO 1 raw").await;
+    assert_eq!(r.error_code(), Some(ErrorCode::InvalidState));
+    assert_eq!(conn.call("PING").await, Response::Ok);
+
+    // без HELLO - прежнее поведение
+    let mut legacy = LineClient::connect(&broker).await;
+    legacy.send("PUB t z\n").await;
+    assert_eq!(legacy.line().await, "ACK");
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tagged_requests_complete_out_of_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut runtime = RuntimeConfig::default();
+    runtime.limits.max_in_flight = 2;
+    let broker = Broker::builder()
+        .data_dir(dir.path())
+        .runtime(runtime)
+        .start()
+        .await
+        .unwrap();
+    // очередь для REQUEST должна существовать
+    broker.publish("rpc", "warmup").await.unwrap();
+
+    let mut conn = LineClient::connect(&broker).await;
+    let mut responder = LineClient::connect(&broker).await;
+    conn.call("HELLO 1 raw ids").await;
+
+    // REQUEST ждет ответа: PING и BEGIN после него приходят раньше
+    conn.send("#1 REQUEST rpc 60000 ping?\n#2 PING\n#3 BEGIN\n")
+        .await;
+    assert_eq!(conn.line().await, "#2 OK");
+    let line = conn.line().await;
+    assert!(line.starts_with("#3 ERR INVALID_STATE "), "{}", line);
+
+    let corr = correlation_id(&broker, "rpc", "ping?").await;
+    let reply = format!("REPLY {} pong", corr);
+    assert_eq!(responder.call(&reply).await, Response::Ok);
+    assert_eq!(conn.line().await, format!("{}\tpong", corr));
+    assert_eq!(conn.line().await, "#1 OK");
+
+    // при пределе в 2 запроса PING читается, только когда освободится место:
+    // после ответа на первый REQUEST, но раньше второго
+    conn.send("#4 REQUEST rpc 60000 a\n#5 REQUEST rpc 60000 b\n#6 PING\n")
+        .await;
+    let (a, b) = (
+        correlation_id(&broker, "rpc", "a").await,
+        correlation_id(&broker, "rpc", "b").await,
+    );
+    responder.call(&format!("REPLY {} ra", a)).await;
+    assert_eq!(conn.line().await, format!("{}\tra", a));
+    assert_eq!(conn.line().await, "#4 OK");
+    assert_eq!(conn.line().await, "#6 OK");
+
+    responder.call(&format!("REPLY {} rb", b)).await;
+    assert_eq!(conn.line().await, format!("{}\trb", b));
+    assert_eq!(conn.line().await, "#5 OK");
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn poll_reads_all_levels_and_rotates_topics() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let mut conn = LineClient::connect(&broker).await;
+    for cmd in [
+        "PUB s.a lo",
+        "PUB s.a PRIO=5 hi",
+        "PUB s.b x1",
+        "PUB s.b x2",
+    ] {
+        assert_eq!(conn.call(cmd).await, Response::Ack);
+    }
+    assert_eq!(conn.call("SUB s.*").await, Response::Ok);
+
+    // <topic> <msg-ref> <payload>
+    let mut poll = async |cmd: &str| -> Vec<String> {
+        let (data, r) = conn.call_data(cmd).await;
+        assert_eq!(r, Response::Ok);
+        data.iter()
+            .map(|line| {
+                let cols: Vec<&str> = line.split('\t').collect();
+                format!("{} {} {}", cols[0], cols[1], cols[3])
+            })
+            .collect()
+    };
+
+    // старший уровень первым; следующий POLL начинает со следующего топика
+    assert_eq!(poll("POLL 1").await, ["s.a 5:0 hi"]);
+    assert_eq!(poll("POLL 1").await, ["s.b 0 x1"]);
+    assert_eq!(poll("POLL 10").await, ["s.a 0 lo", "s.b 1 x2"]);
+    assert!(poll("POLL 10").await.is_empty());
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn memory_topics_reject_priorities_and_receive() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let mut conn = LineClient::connect(&broker).await;
+
+    assert_eq!(conn.call("CREATE m TYPE=memory").await, Response::Ok);
+    for cmd in [
+        "PUB m PRIO=3 x",
+        "MPUB m 1 PRIO=1\ny",
+        "FETCH m 0 10 PRIO=2",
+        "RECEIVE m",
+    ] {
+        let r = conn.call(cmd).await;
+        assert_eq!(r.error_code(), Some(ErrorCode::Conflict), "{}", cmd);
+    }
+    // уровень 0 пишется как обычно
+    assert_eq!(conn.call("PUB m z").await, Response::Ack);
+
+    broker.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exhausted_leases_move_to_dlq_without_receive() {
+    let dir = tempfile::tempdir().unwrap();
+    let broker = start(dir.path()).await;
+    let mut conn = LineClient::connect(&broker).await;
+
+    assert_eq!(
+        conn.call("CREATE q MAXATTEMPTS=1 DLQ=q.dead").await,
+        Response::Ok
+    );
+    assert_eq!(conn.call("PUB q job").await, Response::Ack);
+    let (leased, _) = conn.call_data("RECEIVE q VT=50").await;
+    assert_eq!(leased.len(), 1);
+
+    // RECEIVE больше не вызывается: запись переносит фоновый обход
+    let mut moved = Vec::new();
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        moved = broker
+            .fetch("q.dead", 0, 10)
+            .await
+            .map(|p| p.records)
+            .unwrap_or_default();
+        if !moved.is_empty() {
+            break;
+        }
+    }
+    let payloads: Vec<_> = moved.iter().map(|r| r.payload.as_str()).collect();
+    assert_eq!(payloads, ["job"]);
+
+    broker.shutdown().await;
+}